@@ -33,7 +33,148 @@ pub enum PriceError {
     WrongSettingsPDA,
 
     #[error("Invalud Instruction")]
-    InvalidInstruction
+    InvalidInstruction,
+
+    #[error("Wrong store PDA")]
+    WrongStorePDA,
+
+    #[error("Arithmetic overflow")]
+    Overflow,
+
+    #[error("Wrong multisig PDA")]
+    WrongMultisigPDA,
+
+    #[error("Invalid multisig threshold or signers")]
+    InvalidMultisig,
+
+    #[error("Store is paused")]
+    StorePaused,
+
+    #[error("Basis points above 10000")]
+    InvalidBps,
+
+    #[error("Wrong referral PDA")]
+    WrongReferralPDA,
+
+    #[error("Voucher is not signed by admin")]
+    InvalidVoucher,
+
+    #[error("Voucher has expired")]
+    VoucherExpired,
+
+    #[error("Voucher was already redeemed")]
+    VoucherRedeemed,
+
+    #[error("Wrong order PDA")]
+    WrongOrderPDA,
+
+    #[error("Order price or amount is zero")]
+    InvalidOrder,
+
+    #[error("Order price does not cross")]
+    OrderNotCrossing,
+
+    #[error("Auction floor above start price or empty time range")]
+    InvalidAuction,
+
+    #[error("Wrong receipt PDA")]
+    WrongReceiptPDA,
+
+    #[error("Refund window has closed")]
+    RefundWindowClosed,
+
+    #[error("Wrong vesting PDA")]
+    WrongVestingPDA,
+
+    #[error("Invalid vesting schedule")]
+    InvalidVesting,
+
+    #[error("Wrong price history PDA")]
+    WrongPriceHistoryPDA,
+
+    #[error("Trade exceeds the maximum trade size")]
+    TradeTooLarge,
+
+    #[error("Settings changes must be queued while the timelock is set")]
+    TimelockActive,
+
+    #[error("No settings change is queued")]
+    NoPendingChange,
+
+    #[error("Queued settings change is not due yet")]
+    TimelockNotElapsed,
+
+    #[error("Wrong product PDA")]
+    WrongProductPDA,
+
+    #[error("Product is not for sale")]
+    ProductInactive,
+
+    #[error("Not enough product stock")]
+    OutOfStock,
+
+    #[error("Wrong listing PDA")]
+    WrongListingPDA,
+
+    #[error("Listing price or amount is zero")]
+    InvalidListing,
+
+    #[error("Wrong subscription PDA")]
+    WrongSubscriptionPDA,
+
+    #[error("Subscriptions are closed")]
+    SubscriptionsClosed,
+
+    #[error("Subscription charge is not due yet")]
+    ChargeNotDue,
+
+    #[error("Wrong invoice PDA")]
+    WrongInvoicePDA,
+
+    #[error("Invoice amount is zero, memo too long or expiry passed")]
+    InvalidInvoice,
+
+    #[error("Invoice is paid or expired")]
+    InvoiceNotPayable,
+
+    #[error("Invoice has not expired")]
+    InvoiceNotExpired,
+
+    #[error("Wrong escrow PDA")]
+    WrongEscrowPDA,
+
+    #[error("Escrow amount is zero or escrowed purchases are disabled")]
+    InvalidEscrow,
+
+    #[error("Escrow is not in the required state")]
+    WrongEscrowStatus,
+
+    #[error("Escrow cannot be released yet")]
+    EscrowLocked,
+
+    #[error("Revenue shares must sum to 10000 bps over at most 8 beneficiaries")]
+    InvalidRevenueSplit,
+
+    #[error("Buyer cannot be their own referrer")]
+    SelfReferral,
+
+    #[error("Selling to the store is closed during a Dutch auction")]
+    AuctionActive,
+
+    #[error("Refunds are disabled or the purchase can't be refunded")]
+    RefundsDisabled,
+
+    #[error("Checkout is unavailable while vesting, refunds or the wSOL quote are enabled")]
+    CheckoutUnavailable,
+
+    #[error("Dispute can't be opened once the escrow can be released by anyone")]
+    DisputeWindowClosed,
+
+    #[error("Store token account is not the pinned store vault or mint")]
+    WrongStoreVault,
+
+    #[error("Trade exceeds what the current limit window has left")]
+    TradeLimitExceeded,
 }
 
 impl From<PriceError> for ProgramError {
@@ -0,0 +1,280 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::log::sol_log_data;
+
+use crate::state::{
+    DutchAuction, HistoryMode, Inventory, PriceMode, Product, Quote, RevenueSplit, Settings,
+    SettingsChange, Side, SubscriptionTerms, TradeLimits, VestingSchedule,
+};
+
+/// First field of every event log, so indexers can tell store events apart
+/// from data logged by other programs in the same transaction.
+pub const EVENT_TAG: &[u8] = b"solana_store";
+
+const PROGRAM_DATA: &str = "Program data: ";
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum StoreEvent {
+    StoreInitialized {
+        user: [u8; 32],
+        counter: u32,
+        value: u32,
+    },
+    SettingsUpdated {
+        /// New values of the fields the instruction changed
+        changes: Vec<SettingsField>,
+    },
+    Bought {
+        user: [u8; 32],
+        amount: u64,
+        lamports: u64,
+    },
+    Sold {
+        user: [u8; 32],
+        amount: u64,
+        lamports: u64,
+    },
+    Withdrawn {
+        recipient: [u8; 32],
+        lamports: u64,
+    },
+    ReferralPaid {
+        referrer: [u8; 32],
+        lamports: u64,
+    },
+    ReferralClaimed {
+        referrer: [u8; 32],
+        lamports: u64,
+    },
+    VoucherRedeemed {
+        buyer: [u8; 32],
+        nonce: u64,
+        discount: u64,
+    },
+    OrderPlaced {
+        order: [u8; 32],
+        owner: [u8; 32],
+        side: Side,
+        price: u32,
+        amount: u64,
+    },
+    OrderCancelled {
+        order: [u8; 32],
+    },
+    OrderFilled {
+        order: [u8; 32],
+        counterparty: [u8; 32],
+        price: u32,
+        amount: u64,
+    },
+    Refunded {
+        user: [u8; 32],
+        amount: u64,
+        lamports: u64,
+    },
+    Vested {
+        buyer: [u8; 32],
+        amount: u64,
+    },
+    Claimed {
+        buyer: [u8; 32],
+        amount: u64,
+    },
+    /// A trade reached a cap of the current limit window and paused the store
+    CircuitTripped {
+        window: u64,
+        tokens_sold: u64,
+        lamports_paid: u64,
+    },
+    SettingsChangeQueued {
+        change: SettingsChange,
+        eta: i64,
+    },
+    ProductUpdated {
+        product: Product,
+    },
+    ProductRemoved {
+        product_id: u64,
+    },
+    Listed {
+        listing: [u8; 32],
+        seller: [u8; 32],
+        price: u32,
+        amount: u64,
+    },
+    Delisted {
+        listing: [u8; 32],
+    },
+    Subscribed {
+        subscriber: [u8; 32],
+        subscription: [u8; 32],
+    },
+    SubscriptionCharged {
+        subscriber: [u8; 32],
+        amount: u64,
+        active_until: i64,
+    },
+    SubscriptionCancelled {
+        subscriber: [u8; 32],
+    },
+    InvoiceCreated {
+        invoice: [u8; 32],
+        merchant: [u8; 32],
+        invoice_id: u64,
+        amount: u64,
+    },
+    InvoicePaid {
+        invoice: [u8; 32],
+        payer: [u8; 32],
+        amount: u64,
+        commission: u64,
+    },
+    InvoiceExpired {
+        invoice: [u8; 32],
+    },
+    EscrowOpened {
+        escrow: [u8; 32],
+        buyer: [u8; 32],
+        seller: [u8; 32],
+        lamports: u64,
+    },
+    DeliveryConfirmed {
+        escrow: [u8; 32],
+        release_after: i64,
+    },
+    EscrowReleased {
+        escrow: [u8; 32],
+        seller_lamports: u64,
+        commission: u64,
+    },
+    DisputeOpened {
+        escrow: [u8; 32],
+        by: [u8; 32],
+    },
+    DisputeResolved {
+        escrow: [u8; 32],
+        buyer_lamports: u64,
+        seller_lamports: u64,
+        commission: u64,
+    },
+    RevenueDistributed {
+        lamports: u64,
+        /// Lamports paid to each beneficiary, in the order of the split
+        payouts: Vec<u64>,
+    },
+    PartiallyFilled {
+        user: [u8; 32],
+        requested: u64,
+        filled: u64,
+    },
+    MultisigCreated {
+        multisig: [u8; 32],
+        threshold: u8,
+        signers: Vec<[u8; 32]>,
+    },
+    ReferrerRegistered {
+        referrer: [u8; 32],
+        referral: [u8; 32],
+    },
+    PriceHistoryCreated {
+        history: [u8; 32],
+    },
+    VestingOpened {
+        buyer: [u8; 32],
+        vesting: [u8; 32],
+        escrow: [u8; 32],
+    },
+}
+
+/// A settings field set by an admin instruction, with its new value.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum SettingsField {
+    Admin([u8; 32]),
+    UpdatedPrice(u32),
+    PriceManager([u8; 32]),
+    Treasurer([u8; 32]),
+    Pauser([u8; 32]),
+    Paused(bool),
+    ReferralBps(u16),
+    PriceMode(PriceMode),
+    Auction(DutchAuction),
+    RefundWindow(i64),
+    Vesting(VestingSchedule),
+    Inventory(Inventory),
+    Quote(Quote),
+    History(HistoryMode),
+    Limits(TradeLimits),
+    TimelockDelay(i64),
+    CommissionBps(u16),
+    Subscription(SubscriptionTerms),
+    EscrowTimeout(i64),
+    RevenueSplit(RevenueSplit),
+    StoreMint([u8; 32]),
+    StoreVault([u8; 32]),
+}
+
+impl StoreEvent {
+    /// Lists the fields that differ between `old` and `new`. Accumulators, the
+    /// limit window and the pending change are left out, trades and
+    /// `SettingsChangeQueued` report those.
+    pub fn settings_updated(old: &Settings, new: &Settings) -> Self {
+        let mut changes = Vec::new();
+        macro_rules! diff {
+            ($($field:ident => $variant:ident),* $(,)?) => {
+                $(if old.$field != new.$field {
+                    changes.push(SettingsField::$variant(new.$field.clone()));
+                })*
+            };
+        }
+        diff!(
+            admin => Admin,
+            updated_price => UpdatedPrice,
+            price_manager => PriceManager,
+            treasurer => Treasurer,
+            pauser => Pauser,
+            paused => Paused,
+            referral_bps => ReferralBps,
+            price_mode => PriceMode,
+            auction => Auction,
+            refund_window => RefundWindow,
+            vesting => Vesting,
+            inventory => Inventory,
+            quote => Quote,
+            history => History,
+            limits => Limits,
+            timelock_delay => TimelockDelay,
+            commission_bps => CommissionBps,
+            subscription => Subscription,
+            escrow_timeout => EscrowTimeout,
+            revenue_split => RevenueSplit,
+            store_mint => StoreMint,
+            store_vault => StoreVault,
+        );
+        StoreEvent::SettingsUpdated { changes }
+    }
+
+    /// Writes the event to the transaction log as `Program data: <tag> <event>`.
+    pub fn emit(&self) {
+        if let Ok(data) = self.try_to_vec() {
+            sol_log_data(&[EVENT_TAG, &data]);
+        }
+    }
+
+    /// Parses a single log line, returning `None` for anything that is not a
+    /// store event.
+    pub fn from_log(log: &str) -> Option<Self> {
+        let mut fields = log.strip_prefix(PROGRAM_DATA)?.split(' ');
+        let tag = base64::decode(fields.next()?).ok()?;
+        if tag != EVENT_TAG {
+            return None;
+        }
+        let data = base64::decode(fields.next()?).ok()?;
+        Self::try_from_slice(&data).ok()
+    }
+
+    /// Collects every store event from the log messages of a transaction.
+    pub fn from_logs<S: AsRef<str>>(logs: &[S]) -> Vec<Self> {
+        logs.iter()
+            .filter_map(|log| Self::from_log(log.as_ref()))
+            .collect()
+    }
+}
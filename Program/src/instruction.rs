@@ -10,7 +10,13 @@ use solana_program::program_error::ProgramError;
 
 use crate::{
     id,
-    state::{Price, Settings},
+    state::{
+        DutchAuction, Multisig, Order, Price, Receipt, Referral, Settings, Side, Store, Vesting,
+        VestingSchedule, VoucherNonce, Inventory, Quote, HistoryMode, PriceHistory, TradeLimits,
+        SettingsChange, Product, CartItem, BuySource, Listing, Subscription, SubscriptionFunding,
+        SubscriptionTerms, Invoice, Escrow, RevenueShare,
+    },
+    voucher::Voucher,
 };
 use crate::error::PriceError;
 
@@ -25,31 +31,553 @@ pub enum StoreInstruction {
     /// 1. `[writeble]` price_account, PDA
     /// 2. `[]` settings_account, PDA
     InitializeStore,
-    /// Update price for store. Only admin can do it.
+    /// Update price for store. Only admin can do it, and only while no timelock
+    /// delay is set; otherwise use `QueueSettingsChange`.
     /// Accounts:
-    /// 0. `[signer, writable]` Admin
+    /// 0. `[signer, writable]` Admin, or the admin multisig account
     /// 1. `[writable]` settings_account, PDA
     /// 2. `[]` Rent sysvar
     /// 3. `[]` System program
+    /// 4. `[writable]` price_history_account, PDA, while price changes are recorded
+    /// 5. ..5+M `[signer]` M multisig signers, if the admin is a multisig
     UpdateSettings { admin: [u8; 32], updated_price: u32 },
-    /// Buying SPL with SOL
+    /// Buying SPL with SOL at the settings price, or the Dutch auction price while one runs.
+    /// `source` may instead pick a catalog product, bought at its price out of its stock,
+    /// or a third-party listing, bought at the seller's price with the store keeping
+    /// its commission.
     /// Accounts:
-    /// 0. `[signer]` store authority
-    /// 1. `[writable]` store token account
-    /// 2. `[writable]` user token account
+    /// 0. `[signer, writable]` user account, debit lamports from this account
+    /// 1. `[writable]` user token account, or the user's vesting escrow while vesting is
+    ///    enabled and the purchase is not a listing.
+    ///    The user's associated token address is created when missing if
+    ///    `create_associated` is set.
+    /// 2. `[writable]` store authority, PDA, credit lamports to this account
+    /// 3. `[writable]` store token account, or the token mint in mint-on-demand mode
+    /// 4. `[writable]` settings_account, PDA, trade accumulators are updated
+    /// 5. `[]` token program
+    /// 6. `[]` System program
+    /// 7. `[]` Rent sysvar
+    ///
+    /// Then, in order:
+    /// - `[writable]` receipt_account, PDA, when `receipt_id` is set
+    /// - `[writable]` vesting_account, PDA, while vesting is enabled, except for a listing
+    /// - `[writable]` product_account, PDA, when buying a product. Account 3
+    ///   then holds the product mint.
+    /// - `[writable]` listing_account, PDA, and `[writable]` seller account, credited
+    ///   with the proceeds, when buying a listing. Account 3 is then the listing escrow.
+    /// - when `create_associated` is set, the user pays rent:
+    ///   `[]` token mint, `[]` associated token account program
+    /// - while the quote is wrapped SOL:
+    ///   `[writable]` user wSOL token account, debit the payment from this account,
+    ///   `[writable]` temporary wSOL account, PDA, closed within the instruction,
+    ///   `[]` native mint
+    /// - `[writable]` price_history_account, PDA, while trades are recorded
+    /// - `[writable]` optional referral_account, PDA, credited with the referral share
+    Buy {
+        amount: u64,
+        /// Records a receipt under this id so the purchase can be refunded.
+        /// Fails with `RefundsDisabled` when refunds are off or for a listing.
+        receipt_id: Option<u64>,
+        source: BuySource,
+        /// Fill what the store token account, product stock or listing holds when
+        /// short of `amount` and charge only for that, instead of failing with
+        /// `InsufficientFunds` or `OutOfStock`
+        allow_partial: bool,
+        /// Account 1 is the user's associated token address, created when missing
+        create_associated: bool,
+    },
+    /// Selling SPL for SOL at the settings price. Rejected during a Dutch auction.
+    /// Accounts:
+    /// 0. `[signer, writable]` user account, credit lamports to this account
+    /// 1. `[writable]` user token account
+    /// 2. `[writable]` store authority, PDA, debit lamports from this account
+    /// 3. `[writable]` store token account, or the token mint in mint-on-demand mode
+    /// 4. `[writable]` settings_account, PDA, trade accumulators are updated
+    /// 5. `[]` token program
+    /// 6. `[]` System program
+    ///
+    /// Then, in order:
+    /// - `[writable]` user wSOL token account, credited instead of the user
+    ///   account while the quote is wrapped SOL
+    /// - `[writable]` price_history_account, PDA, while trades are recorded
+    Sell { amount: u64 },
+    /// Selling SPL for SOL without the user's signature. The user must have
+    /// approved the store PDA as delegate for at least `amount` tokens.
+    /// Rejected during a Dutch auction.
+    /// Accounts:
+    /// 0. `[writable]` store authority, PDA
+    /// 1. `[writable]` user token account, delegated to the store PDA
+    /// 2. `[writable]` store token account, or the token mint in mint-on-demand mode
+    /// 3. `[writable]` user account, owner of the user token account
+    /// 4. `[writable]` settings_account, PDA, trade accumulators are updated
+    /// 5. `[]` token program
+    /// 6. `[]` System program
+    /// 7. `[writable]` user wSOL token account, credited instead of the user
+    ///    account while the quote is wrapped SOL
+    SellDelegated { amount: u64 },
+    /// Create an M-of-N multisig that can be set as the settings admin
+    /// Accounts:
+    /// 0. `[signer, writable]` creator, pays for the account
+    /// 1. `[writable]` multisig_account, PDA
+    /// 2. `[]` Rent sysvar
+    /// 3. `[]` System program
+    CreateMultisig { threshold: u8, signers: Vec<[u8; 32]> },
+    /// Update the store price and end any Dutch auction. Only the price manager can do it,
+    /// and only while no timelock delay is set.
+    /// Accounts:
+    /// 0. `[signer]` price manager, or its multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. `[writable]` price_history_account, PDA, while price changes are recorded
+    /// 3. ..3+M `[signer]` M multisig signers, if the price manager is a multisig
+    UpdatePrice { updated_price: u32 },
+    /// Pause or resume trading. Only the pauser can do it.
+    /// Accounts:
+    /// 0. `[signer]` pauser, or its multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the pauser is a multisig
+    SetPaused { paused: bool },
+    /// Rotate the price manager, treasurer and pauser. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetRoles {
+        price_manager: [u8; 32],
+        treasurer: [u8; 32],
+        pauser: [u8; 32],
+    },
+    /// Withdraw lamports from the store PDA. Only the treasurer can do it.
+    /// Accounts:
+    /// 0. `[signer]` treasurer, or its multisig account
+    /// 1. `[]` settings_account, PDA
+    /// 2. `[writable]` store authority, PDA
+    /// 3. `[writable]` recipient
+    /// 4. `[]` System program
+    /// 5. ..5+M `[signer]` M multisig signers, if the treasurer is a multisig
+    Withdraw { lamports: u64 },
+    /// Set the referral share of each purchase. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetReferralBps { referral_bps: u16 },
+    /// Create the referral account that accumulates a referrer's rewards
+    /// Accounts:
+    /// 0. `[signer, writable]` referrer, pays for the account
+    /// 1. `[writable]` referral_account, PDA
+    /// 2. `[]` Rent sysvar
+    /// 3. `[]` System program
+    RegisterReferrer,
+    /// Move all unclaimed referral rewards to the referrer
+    /// Accounts:
+    /// 0. `[signer, writable]` referrer
+    /// 1. `[writable]` referral_account, PDA
+    ClaimReferral,
+    /// Buying SPL with SOL at a discount. The transaction must carry, right
+    /// before this instruction, an Ed25519 program instruction verifying the
+    /// admin's signature over `voucher.message()`.
+    /// Accounts:
+    /// 0-7. As in `Buy`, followed by its receipt and vesting accounts when used
+    /// - `[]` Instructions sysvar
+    /// - `[writable]` voucher nonce, PDA of the buyer and nonce, assigned to the program
+    ///   to mark the voucher redeemed
+    ///
+    /// Then the remaining accounts of `Buy`
+    BuyWithVoucher {
+        amount: u64,
+        receipt_id: Option<u64>,
+        voucher: Voucher,
+        /// As in `Buy`
+        create_associated: bool,
+    },
+    /// Place a limit order at the user's own price. Sell orders escrow the
+    /// tokens, buy orders escrow `price * amount` lamports in the order PDA.
+    /// Accounts:
+    /// 0. `[signer, writable]` owner, pays for the order account
+    /// 1. `[writable]` order_account, PDA
+    /// 2. `[writable]` owner token account, source of sold or receiver of bought tokens
+    /// 3. `[]` store token account or token mint, fixes the traded mint
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    /// 6. `[]` token program
+    /// 7. `[writable]` escrow token account owned by the order PDA, sell orders only
+    PlaceOrder {
+        order_id: u64,
+        side: Side,
+        price: u32,
+        amount: u64,
+    },
+    /// Cancel an order, returning the escrowed tokens or lamports and the rent
+    /// Accounts:
+    /// 0. `[signer, writable]` owner
+    /// 1. `[writable]` order_account, PDA
+    /// 2. `[writable]` order token account, as recorded in the order
+    /// 3. `[]` token program
+    /// 4. `[writable]` owner token account receiving the escrow, sell orders only
+    CancelOrder,
+    /// Fill up to `amount` of an order at its price, against the store or
+    /// against a crossing order on the other side. Anyone can do it. The store
+    /// doesn't fill sell orders during a Dutch auction.
+    /// Accounts:
+    /// 0. `[writable]` order_account, PDA
+    /// 1. `[writable]` order token account, as recorded in the order
+    /// 2. `[writable]` order owner
     /// 3. `[]` token program
-    /// 4. `[signer, writable]` Debit lamports from this account
-    /// 5. `[writable]` Credit lamports to this account
+    /// 4. `[]` System program
+    ///
+    /// Against the store:
+    /// 5. `[writable]` store authority, PDA
+    /// 6. `[writable]` store token account, or the token mint in mint-on-demand mode
+    /// 7. `[writable]` settings_account, PDA, trade limits are tracked
+    ///
+    /// Against another order:
+    /// 5. `[writable]` counter order_account, PDA
+    /// 6. `[writable]` counter order token account
+    /// 7. `[writable]` counter order owner
+    FillOrder { amount: u64 },
+    /// Switch Buy to a Dutch auction price. Only the price manager can do it,
+    /// and only while no timelock delay is set.
+    /// `UpdatePrice` or `UpdateSettings` switch back to the fixed price.
+    /// Accounts:
+    /// 0. `[signer]` price manager, or its multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the price manager is a multisig
+    StartDutchAuction { auction: DutchAuction },
+    /// Set how long purchases stay refundable, 0 disables refunds. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetRefundWindow { refund_window: i64 },
+    /// Return the purchased tokens to the store and get back the lamports the
+    /// store received, within the refund window of the receipt
+    /// Accounts:
+    /// 0. `[signer, writable]` user account, the buyer
+    /// 1. `[writable]` user token account
+    /// 2. `[writable]` store authority, PDA
+    /// 3. `[writable]` store token account, or the token mint in mint-on-demand mode
+    /// 4. `[]` settings_account, PDA
+    /// 5. `[]` token program
     /// 6. `[]` System program
-    Buy { amount: u64 },
-    /// 0. `[signer]` user authority
+    /// 7. `[writable]` receipt_account, PDA, closed to the buyer
+    ///
+    /// Then, in order:
+    /// - `[writable]` product_account, PDA, restocked when the receipt is for a product.
+    ///   Account 3 then holds the product mint.
+    /// - `[writable]` user wSOL token account, credited instead of the user
+    ///   account while the quote is wrapped SOL
+    Refund,
+    /// Set the vesting schedule of bought tokens, an `end_ts` of 0 disables
+    /// vesting. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetVesting { vesting: VestingSchedule },
+    /// Create the user's vesting account and escrow token account, needed
+    /// before buying while vesting is enabled
+    /// Accounts:
+    /// 0. `[signer, writable]` user account, pays for both accounts
+    /// 1. `[writable]` vesting_account, PDA
+    /// 2. `[writable]` vesting escrow token account, PDA
+    /// 3. `[]` token mint
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    /// 6. `[]` token program
+    OpenVesting,
+    /// Release the vested part of the user's tokens
+    /// Accounts:
+    /// 0. `[signer]` user account
     /// 1. `[writable]` user token account
-    /// 2. `[writable]` store token account
+    /// 2. `[writable]` vesting_account, PDA
+    /// 3. `[writable]` vesting escrow token account, PDA
+    /// 4. `[]` settings_account, PDA
+    /// 5. `[]` token program
+    Claim,
+    /// Switch between selling out of the store token account and minting on
+    /// demand. Minting requires the store PDA to be the mint authority.
+    /// Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetInventory { inventory: Inventory },
+    /// Settle trades in native lamports or through wrapped SOL token accounts.
+    /// Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetQuote { quote: Quote },
+    /// Create the store's price history account
+    /// Accounts:
+    /// 0. `[signer, writable]` payer
+    /// 1. `[writable]` price_history_account, PDA
+    /// 2. `[]` Rent sysvar
+    /// 3. `[]` System program
+    CreatePriceHistory,
+    /// Choose what is appended to the price history. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. `[]` price_history_account, PDA, must exist unless turning history off
+    /// 3. ..3+M `[signer]` M multisig signers, if the admin is a multisig
+    SetHistoryMode { history: HistoryMode },
+    /// Set the per-period caps and maximum trade size. Buy, Sell and fills
+    /// against the store count towards the caps, and the trade reaching a cap
+    /// pauses the store. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetLimits { limits: TradeLimits },
+    /// Queue an admin, price, timelock delay and product price change, executable
+    /// once the current timelock delay has passed. Replaces any queued change.
+    /// Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    QueueSettingsChange { change: SettingsChange },
+    /// Apply the queued settings change after its ETA. Anyone can do it.
+    /// Accounts:
+    /// 0. `[writable]` settings_account, PDA
+    /// 1. `[writable]` product_account, PDA, if the change sets a product price
+    /// 2. `[writable]` price_history_account, PDA, while price changes are recorded
+    ExecuteSettingsChange,
+    /// Add a product to the catalog. Only admin can do it, and only while no
+    /// timelock delay is set.
+    /// Accounts:
+    /// 0. `[signer, writable]` payer
+    /// 1. `[signer]` Admin, or the admin multisig account
+    /// 2. `[]` settings_account, PDA
+    /// 3. `[writable]` product_account, PDA
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    /// 6. ..6+M `[signer]` M multisig signers, if the admin is a multisig
+    AddProduct {
+        product_id: u64,
+        mint: [u8; 32],
+        price: u32,
+        stock: u64,
+    },
+    /// Set a product's price, stock and whether it is for sale. Only admin can do it.
+    /// While a timelock delay is set the price can't change here, queue it with
+    /// `QueueSettingsChange` instead.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[]` settings_account, PDA
+    /// 2. `[writable]` product_account, PDA
+    /// 3. ..3+M `[signer]` M multisig signers, if the admin is a multisig
+    UpdateProduct { price: u32, stock: u64, active: bool },
+    /// Remove a product from the catalog. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[]` settings_account, PDA
+    /// 2. `[writable]` product_account, PDA, closed
+    /// 3. `[writable]` recipient of the product account rent
+    /// 4. ..4+M `[signer]` M multisig signers, if the admin is a multisig
+    RemoveProduct,
+    /// Buy several products at once with a single payment. The whole cart
+    /// fails if any item is inactive or out of stock. Unavailable while vesting,
+    /// refunds or the wSOL quote are enabled; use `Buy` for each product then.
+    /// Accounts:
+    /// 0. `[signer, writable]` user account, debit lamports from this account
+    /// 1. `[writable]` store authority, PDA, credit lamports to this account
+    /// 2. `[writable]` settings_account, PDA, trade limits are tracked
+    /// 3. `[]` token program
+    /// 4. `[]` System program
+    ///
+    /// Then for each item, in cart order:
+    /// - `[writable]` product_account, PDA
+    /// - `[writable]` store token account of the product mint, or the mint in mint-on-demand mode
+    /// - `[writable]` user token account of the product mint
+    Checkout { items: Vec<CartItem> },
+    /// Offer tokens for sale through Buy at the seller's price. The tokens move
+    /// into an escrow token account owned by the listing PDA.
+    /// Accounts:
+    /// 0. `[signer, writable]` seller, pays for the listing account
+    /// 1. `[writable]` seller token account
+    /// 2. `[writable]` listing_account, PDA
+    /// 3. `[writable]` escrow token account, owned by the listing PDA
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    /// 6. `[]` token program
+    ListTokens {
+        listing_id: u64,
+        price: u32,
+        amount: u64,
+    },
+    /// Return the unsold tokens to the seller and close the listing
+    /// Accounts:
+    /// 0. `[signer, writable]` seller
+    /// 1. `[writable]` listing_account, PDA
+    /// 2. `[writable]` escrow token account
+    /// 3. `[writable]` seller token account
+    /// 4. `[]` token program
+    Delist,
+    /// Set the share of listing sales kept by the store. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetCommissionBps { commission_bps: u16 },
+    /// Set the price and period of subscriptions. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetSubscriptionTerms { terms: SubscriptionTerms },
+    /// Open a subscription, charged from the next `ChargeSubscription`.
+    /// Prefunded subscriptions can be topped up with a plain lamport transfer.
+    /// Accounts:
+    /// 0. `[signer, writable]` subscriber, pays for the subscription account and the deposit
+    /// 1. `[writable]` subscription_account, PDA
+    /// 2. `[]` settings_account, PDA
+    /// 3. `[]` token account receiving the delivered store tokens
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    /// 6. `[]` payment token account that approved the subscription PDA as delegate,
+    ///    if delegated. Approving the subscription rather than the store keeps the
+    ///    allowance out of reach of every other store instruction.
+    Subscribe {
+        funding: SubscriptionFunding,
+        deposit: u64,
+    },
+    /// Charge a subscription that is due and extend it by one period. Anyone can crank it.
+    /// Accounts:
+    /// 0. `[writable]` subscription_account, PDA
+    /// 1. `[writable]` store PDA
+    /// 2. `[]` settings_account, PDA
     /// 3. `[]` token program
-    /// 4. `[signer, writable]` Debit lamports from this account
-    /// 5. `[writable]` Credit lamports to this account
+    ///
+    /// Then, in order:
+    /// - if delegated: `[writable]` payment token account, `[writable]` store
+    ///   token account of the payment mint
+    /// - if tokens are delivered: `[writable]` store token account (the mint in
+    ///   mint-on-demand mode), `[writable]` delivery token account
+    ChargeSubscription,
+    /// Close the subscription and return its lamports to the subscriber
+    /// Accounts:
+    /// 0. `[signer, writable]` subscriber
+    /// 1. `[writable]` subscription_account, PDA
+    CancelSubscription,
+    /// Bill a buyer `amount` of `mint`, or of lamports when `mint` is all zeros
+    /// Accounts:
+    /// 0. `[signer, writable]` merchant, pays for the invoice account
+    /// 1. `[writable]` invoice_account, PDA
+    /// 2. `[]` Rent sysvar
+    /// 3. `[]` System program
+    CreateInvoice {
+        invoice_id: u64,
+        amount: u64,
+        mint: [u8; 32],
+        memo: String,
+        expiry: i64,
+    },
+    /// Pay an open invoice. The store keeps `commission_bps` of the amount.
+    /// Lamport invoices are paid in the store quote, like Buy.
+    /// Accounts:
+    /// 0. `[signer, writable]` payer
+    /// 1. `[writable]` invoice_account, PDA
+    /// 2. `[writable]` merchant, or the merchant's token account for token invoices
+    /// 3. `[writable]` store PDA
+    /// 4. `[]` settings_account, PDA
+    /// 5. `[]` Rent sysvar
     /// 6. `[]` System program
-    Sell { amount: u64 },
+    /// 7. `[]` token program
+    ///
+    /// Then, in order:
+    /// - token invoices: `[writable]` payer token account, `[writable]` store
+    ///   token account of the invoice mint
+    /// - lamport invoices while the quote is wrapped SOL: `[writable]` payer
+    ///   wSOL account, `[writable]` temporary wSOL account PDA, `[]` native mint
+    PayInvoice,
+    /// Mark an open invoice past its expiry as expired. Anyone can do it.
+    /// Accounts:
+    /// 0. `[writable]` invoice_account, PDA
+    ExpireInvoice,
+    /// Set how long after delivery an escrow releases itself. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetEscrowTimeout { escrow_timeout: i64 },
+    /// Pay `lamports` for a seller's goods into an escrow PDA
+    /// Accounts:
+    /// 0. `[signer, writable]` buyer, pays for the escrow account and the goods
+    /// 1. `[writable]` escrow_account, PDA
+    /// 2. `[]` seller
+    /// 3. `[]` settings_account, PDA
+    /// 4. `[]` Rent sysvar
+    /// 5. `[]` System program
+    OpenEscrow { escrow_id: u64, lamports: u64 },
+    /// Confirm the goods were delivered, starting the release timeout
+    /// Accounts:
+    /// 0. `[signer]` seller
+    /// 1. `[writable]` escrow_account, PDA
+    /// 2. `[]` settings_account, PDA
+    ConfirmDelivery,
+    /// Pay the seller, less the store commission, and close the escrow to the
+    /// buyer. The buyer can do it at any time, anyone else once the timeout
+    /// after delivery has passed.
+    /// Accounts:
+    /// 0. `[signer]` buyer, or anyone after the timeout
+    /// 1. `[writable]` escrow_account, PDA
+    /// 2. `[writable]` buyer
+    /// 3. `[writable]` seller
+    /// 4. `[writable]` store PDA
+    /// 5. `[]` settings_account, PDA
+    ReleaseEscrow,
+    /// Freeze the escrow until the admin resolves it. Only possible before the
+    /// timeout after delivery has passed.
+    /// Accounts:
+    /// 0. `[signer]` buyer or seller
+    /// 1. `[writable]` escrow_account, PDA
+    OpenDispute,
+    /// Refund `buyer_bps` of a disputed escrow to the buyer and pay the rest to
+    /// the seller, less the store commission. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[]` settings_account, PDA
+    /// 2. `[writable]` escrow_account, PDA
+    /// 3. `[writable]` buyer
+    /// 4. `[writable]` seller
+    /// 5. `[writable]` store PDA
+    /// 6. ..6+M `[signer]` M multisig signers, if the admin is a multisig
+    ResolveDispute { buyer_bps: u16 },
+    /// Set the beneficiaries of the treasury, whose shares must sum to 10000 bps.
+    /// No shares clears the split. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer]` Admin, or the admin multisig account
+    /// 1. `[writable]` settings_account, PDA
+    /// 2. ..2+M `[signer]` M multisig signers, if the admin is a multisig
+    SetRevenueSplit { shares: Vec<RevenueShare> },
+    /// Pay the store PDA balance above its rent-exempt minimum out to the
+    /// revenue split. Only the treasurer can do it.
+    /// Accounts:
+    /// 0. `[signer]` treasurer, or its multisig account
+    /// 1. `[]` settings_account, PDA
+    /// 2. `[writable]` store authority, PDA
+    /// 3. `[]` System program
+    /// 4. ..4+N `[writable]` N beneficiaries, in the order of the split
+    ///
+    /// Then `[signer]` M multisig signers, if the treasurer is a multisig
+    Distribute,
+    /// Grow a settings account written by an earlier version of the program to
+    /// the current layout, new fields taking their defaults, and pin the store
+    /// mint and vault. Trades only move store tokens through the pinned vault,
+    /// or the pinned mint in mint-on-demand mode. Only admin can do it.
+    /// Accounts:
+    /// 0. `[signer, writable]` payer, tops up the rent of the larger account
+    /// 1. `[signer]` Admin, or the admin multisig account
+    /// 2. `[writable]` settings_account, PDA
+    /// 3. `[]` System program
+    /// 4. `[]` store mint
+    /// 5. `[]` store vault, token account of the store mint owned by the store PDA,
+    ///    or the store mint again when the store only mints on demand
+    /// 6. ..6+M `[signer]` M multisig signers, if the admin is a multisig
+    MigrateSettings,
 }
 
 impl StoreInstruction {
@@ -73,6 +601,7 @@ impl StoreInstruction {
         updated_price: u32,
     ) -> Instruction {
         let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
         Instruction::new_with_borsh(
             id(),
             &StoreInstruction::UpdateSettings {
@@ -84,54 +613,1248 @@ impl StoreInstruction {
                 AccountMeta::new(settings_pubkey, false),
                 AccountMeta::new_readonly(sysvar::rent::id(), false),
                 AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(history_pubkey, false),
             ],
         )
     }
     pub fn buy(
-        pool_owner: &Pubkey,
         user: &Pubkey,
-        admin_token_account: &Pubkey,
         user_token_account: &Pubkey,
-        token_program: &Pubkey,
+        store_token_account: &Pubkey,
         amount: u64,
+        receipt_id: Option<u64>,
     ) -> Instruction {
         Instruction::new_with_borsh(
             id(),
             &StoreInstruction::Buy {
-                amount
+                amount,
+                receipt_id,
+                source: BuySource::Store,
+                allow_partial: false,
+                create_associated: false,
             },
-            vec![
-                AccountMeta::new(*pool_owner, true),
-                AccountMeta::new(*user, false),
-                AccountMeta::new(*admin_token_account, false),
-                AccountMeta::new(*user_token_account, false),
-                AccountMeta::new(*token_program, false),
-                AccountMeta::new_readonly(spl_token::id(), false)
-            ]
+            Self::buy_accounts(user, user_token_account, store_token_account, receipt_id),
         )
     }
 
-    pub fn sell(
-        // pool_owner: &Pubkey,
+    /// Buy into the user's vesting escrow, for stores with vesting enabled
+    pub fn buy_vested(
+        user: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        let (vesting_pubkey, _) = Vesting::get_vesting_pubkey(user);
+        let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(user);
+        let mut accounts =
+            Self::buy_accounts(user, &escrow_pubkey, store_token_account, receipt_id);
+        accounts.push(AccountMeta::new(vesting_pubkey, false));
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source: BuySource::Store,
+                allow_partial: false,
+                create_associated: false,
+            },
+            accounts,
+        )
+    }
+
+    /// Buy up to `amount`, filling only what the store token account holds
+    pub fn buy_partial(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source: BuySource::Store,
+                allow_partial: true,
+                create_associated: false,
+            },
+            Self::buy_accounts(user, user_token_account, store_token_account, receipt_id),
+        )
+    }
+
+    pub fn buy_with_referrer(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        referrer: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        Self::with_referrer(
+            Self::buy(user, user_token_account, store_token_account, amount, receipt_id),
+            referrer,
+        )
+    }
+
+    /// Appends the referral account of `referrer` to a `Buy` or `BuyWithVoucher`
+    /// from the other builders. The processor reads it last, so any price
+    /// history account must already be in place.
+    pub fn with_referrer(mut buy: Instruction, referrer: &Pubkey) -> Instruction {
+        let (referral_pubkey, _) = Referral::get_referral_pubkey(referrer);
+        buy.accounts.push(AccountMeta::new(referral_pubkey, false));
+        buy
+    }
+
+    /// Buy `amount` of a catalog product, `store_token_account` must hold the product mint
+    pub fn buy_product(
         user: &Pubkey,
-        admin_token_account: &Pubkey,
         user_token_account: &Pubkey,
-        token_program: &Pubkey,
+        store_token_account: &Pubkey,
+        product_id: u64,
         amount: u64,
+        receipt_id: Option<u64>,
     ) -> Instruction {
+        let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+        let mut accounts =
+            Self::buy_accounts(user, user_token_account, store_token_account, receipt_id);
+        accounts.push(AccountMeta::new(product_pubkey, false));
         Instruction::new_with_borsh(
             id(),
             &StoreInstruction::Buy {
-                amount
+                amount,
+                receipt_id,
+                source: BuySource::Product(product_id),
+                allow_partial: false,
+                create_associated: false,
             },
+            accounts,
+        )
+    }
+
+    pub fn buy_listing(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        listing: &Listing,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        let seller = Pubkey::new_from_array(listing.seller);
+        let (listing_pubkey, _) = Listing::get_listing_pubkey(&seller, listing.listing_id);
+        let mut accounts = Self::buy_accounts(
+            user,
+            user_token_account,
+            &Pubkey::new_from_array(listing.escrow),
+            receipt_id,
+        );
+        accounts.extend(vec![
+            AccountMeta::new(listing_pubkey, false),
+            AccountMeta::new(seller, false),
+        ]);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source: BuySource::Listing,
+                allow_partial: false,
+                create_associated: false,
+            },
+            accounts,
+        )
+    }
+
+    /// Buy into the user's associated token account, created if it doesn't exist yet
+    pub fn buy_to_associated(
+        user: &Pubkey,
+        mint: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        let user_token_account =
+            spl_associated_token_account::get_associated_token_address(user, mint);
+        let mut accounts =
+            Self::buy_accounts(user, &user_token_account, store_token_account, receipt_id);
+        accounts.extend(vec![
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        ]);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source: BuySource::Store,
+                allow_partial: false,
+                create_associated: true,
+            },
+            accounts,
+        )
+    }
+
+    /// Buy paying from the user's wSOL token account, for stores quoted in wrapped SOL
+    pub fn buy_with_wsol(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        user_wsol_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+    ) -> Instruction {
+        let (wsol_pubkey, _) = Store::get_wsol_pubkey(user);
+        let mut accounts =
+            Self::buy_accounts(user, user_token_account, store_token_account, receipt_id);
+        accounts.extend(vec![
+            AccountMeta::new(*user_wsol_account, false),
+            AccountMeta::new(wsol_pubkey, false),
+            AccountMeta::new_readonly(spl_token::native_mint::id(), false),
+        ]);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source: BuySource::Store,
+                allow_partial: false,
+                create_associated: false,
+            },
+            accounts,
+        )
+    }
+
+    fn buy_accounts(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        receipt_id: Option<u64>,
+    ) -> Vec<AccountMeta> {
+        let mut accounts = Self::trade_accounts(user, user_token_account, store_token_account);
+        accounts.push(AccountMeta::new_readonly(sysvar::rent::id(), false));
+        if let Some(receipt_id) = receipt_id {
+            let (receipt_pubkey, _) = Receipt::get_receipt_pubkey(user, receipt_id);
+            accounts.push(AccountMeta::new(receipt_pubkey, false));
+        }
+        accounts
+    }
+
+    pub fn refund(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        receipt_id: u64,
+    ) -> Instruction {
+        let (receipt_pubkey, _) = Receipt::get_receipt_pubkey(user, receipt_id);
+        let mut accounts = Self::trade_accounts(user, user_token_account, store_token_account);
+        accounts.push(AccountMeta::new(receipt_pubkey, false));
+        Instruction::new_with_borsh(id(), &StoreInstruction::Refund, accounts)
+    }
+
+    /// Refund of a product purchase, returning the tokens to the product's stock
+    pub fn refund_product(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        receipt_id: u64,
+        product_id: u64,
+    ) -> Instruction {
+        let (receipt_pubkey, _) = Receipt::get_receipt_pubkey(user, receipt_id);
+        let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+        let mut accounts = Self::trade_accounts(user, user_token_account, store_token_account);
+        accounts.extend(vec![
+            AccountMeta::new(receipt_pubkey, false),
+            AccountMeta::new(product_pubkey, false),
+        ]);
+        Instruction::new_with_borsh(id(), &StoreInstruction::Refund, accounts)
+    }
+
+    pub fn sell(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Sell { amount },
+            Self::trade_accounts(user, user_token_account, store_token_account),
+        )
+    }
+
+    /// Sell paying into the user's wSOL token account, for stores quoted in wrapped SOL
+    pub fn sell_with_wsol(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        user_wsol_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        let mut accounts = Self::trade_accounts(user, user_token_account, store_token_account);
+        accounts.push(AccountMeta::new(*user_wsol_account, false));
+        Instruction::new_with_borsh(id(), &StoreInstruction::Sell { amount }, accounts)
+    }
+
+    fn trade_accounts(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        let (store_pubkey, _) = Store::get_store_pubkey();
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*user_token_account, false),
+            AccountMeta::new(store_pubkey, false),
+            AccountMeta::new(*store_token_account, false),
+            AccountMeta::new(settings_pubkey, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ]
+    }
+
+    pub fn sell_delegated(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        let (store_pubkey, _) = Store::get_store_pubkey();
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SellDelegated { amount },
             vec![
-                // AccountMeta::new(*pool_owner, true),
-                AccountMeta::new(*user, true),
-                AccountMeta::new(*admin_token_account, false),
+                AccountMeta::new(store_pubkey, false),
                 AccountMeta::new(*user_token_account, false),
-                AccountMeta::new(*token_program, false),
-                AccountMeta::new_readonly(spl_token::id(), false)
-            ]
+                AccountMeta::new(*store_token_account, false),
+                AccountMeta::new(*user, false),
+                AccountMeta::new(settings_pubkey, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn create_multisig(creator: &Pubkey, threshold: u8, signers: &[Pubkey]) -> Instruction {
+        let (multisig_pubkey, _) = Multisig::get_multisig_pubkey(creator);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::CreateMultisig {
+                threshold,
+                signers: signers.iter().map(|signer| signer.to_bytes()).collect(),
+            },
+            vec![
+                AccountMeta::new(*creator, true),
+                AccountMeta::new(multisig_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn update_price_multisig(
+        multisig: &Pubkey,
+        signers: &[&Pubkey],
+        new_admin: [u8; 32],
+        updated_price: u32,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*multisig, false),
+            AccountMeta::new(settings_pubkey, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(history_pubkey, false),
+        ];
+        accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(**signer, true)));
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::UpdateSettings {
+                admin: new_admin,
+                updated_price,
+            },
+            accounts,
+        )
+    }
+
+    pub fn set_price(price_manager: &Pubkey, updated_price: u32) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::UpdatePrice { updated_price },
+            vec![
+                AccountMeta::new_readonly(*price_manager, true),
+                AccountMeta::new(settings_pubkey, false),
+                AccountMeta::new(history_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_paused(pauser: &Pubkey, paused: bool) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetPaused { paused },
+            vec![
+                AccountMeta::new_readonly(*pauser, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_roles(
+        admin: &Pubkey,
+        signers: &[&Pubkey],
+        price_manager: &Pubkey,
+        treasurer: &Pubkey,
+        pauser: &Pubkey,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*admin, signers.is_empty()),
+            AccountMeta::new(settings_pubkey, false),
+        ];
+        accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(**signer, true)));
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetRoles {
+                price_manager: price_manager.to_bytes(),
+                treasurer: treasurer.to_bytes(),
+                pauser: pauser.to_bytes(),
+            },
+            accounts,
+        )
+    }
+
+    pub fn withdraw(
+        treasurer: &Pubkey,
+        signers: &[&Pubkey],
+        recipient: &Pubkey,
+        lamports: u64,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (store_pubkey, _) = Store::get_store_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*treasurer, signers.is_empty()),
+            AccountMeta::new_readonly(settings_pubkey, false),
+            AccountMeta::new(store_pubkey, false),
+            AccountMeta::new(*recipient, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(**signer, true)));
+        Instruction::new_with_borsh(id(), &StoreInstruction::Withdraw { lamports }, accounts)
+    }
+
+    pub fn set_referral_bps(admin: &Pubkey, referral_bps: u16) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetReferralBps { referral_bps },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn register_referrer(referrer: &Pubkey) -> Instruction {
+        let (referral_pubkey, _) = Referral::get_referral_pubkey(referrer);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::RegisterReferrer,
+            vec![
+                AccountMeta::new(*referrer, true),
+                AccountMeta::new(referral_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn claim_referral(referrer: &Pubkey) -> Instruction {
+        let (referral_pubkey, _) = Referral::get_referral_pubkey(referrer);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ClaimReferral,
+            vec![
+                AccountMeta::new(*referrer, true),
+                AccountMeta::new(referral_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn buy_with_voucher(
+        user: &Pubkey,
+        user_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        amount: u64,
+        receipt_id: Option<u64>,
+        voucher: Voucher,
+    ) -> Instruction {
+        let (nonce_pubkey, _) = VoucherNonce::get_nonce_pubkey(user, voucher.nonce);
+        let mut accounts =
+            Self::buy_accounts(user, user_token_account, store_token_account, receipt_id);
+        accounts.extend(vec![
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+            AccountMeta::new(nonce_pubkey, false),
+        ]);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::BuyWithVoucher {
+                amount,
+                receipt_id,
+                voucher,
+                create_associated: false,
+            },
+            accounts,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_order(
+        owner: &Pubkey,
+        owner_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+        escrow_token_account: Option<&Pubkey>,
+        order_id: u64,
+        side: Side,
+        price: u32,
+        amount: u64,
+    ) -> Instruction {
+        let (order_pubkey, _) = Order::get_order_pubkey(owner, order_id);
+        let mut accounts = vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(order_pubkey, false),
+            AccountMeta::new(*owner_token_account, false),
+            AccountMeta::new_readonly(*store_token_account, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ];
+        if let Some(escrow_token_account) = escrow_token_account {
+            accounts.push(AccountMeta::new(*escrow_token_account, false));
+        }
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::PlaceOrder {
+                order_id,
+                side,
+                price,
+                amount,
+            },
+            accounts,
+        )
+    }
+
+    pub fn cancel_order(
+        owner: &Pubkey,
+        order_id: u64,
+        order_token_account: &Pubkey,
+        owner_token_account: Option<&Pubkey>,
+    ) -> Instruction {
+        let (order_pubkey, _) = Order::get_order_pubkey(owner, order_id);
+        let mut accounts = vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(order_pubkey, false),
+            AccountMeta::new(*order_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ];
+        if let Some(owner_token_account) = owner_token_account {
+            accounts.push(AccountMeta::new(*owner_token_account, false));
+        }
+        Instruction::new_with_borsh(id(), &StoreInstruction::CancelOrder, accounts)
+    }
+
+    pub fn fill_order_with_store(
+        order: &Order,
+        store_token_account: &Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        let (store_pubkey, _) = Store::get_store_pubkey();
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut accounts = Self::order_accounts(order);
+        accounts.extend(vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(store_pubkey, false),
+            AccountMeta::new(*store_token_account, false),
+            AccountMeta::new(settings_pubkey, false),
+        ]);
+        Instruction::new_with_borsh(id(), &StoreInstruction::FillOrder { amount }, accounts)
+    }
+
+    pub fn fill_orders(order: &Order, counter_order: &Order, amount: u64) -> Instruction {
+        let mut accounts = Self::order_accounts(order);
+        accounts.extend(vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ]);
+        accounts.extend(Self::order_accounts(counter_order));
+        Instruction::new_with_borsh(id(), &StoreInstruction::FillOrder { amount }, accounts)
+    }
+
+    fn order_accounts(order: &Order) -> Vec<AccountMeta> {
+        let owner = Pubkey::new_from_array(order.owner);
+        let (order_pubkey, _) = Order::get_order_pubkey(&owner, order.order_id);
+        vec![
+            AccountMeta::new(order_pubkey, false),
+            AccountMeta::new(Pubkey::new_from_array(order.token_account), false),
+            AccountMeta::new(owner, false),
+        ]
+    }
+
+    pub fn start_dutch_auction(price_manager: &Pubkey, auction: DutchAuction) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::StartDutchAuction { auction },
+            vec![
+                AccountMeta::new_readonly(*price_manager, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_refund_window(admin: &Pubkey, refund_window: i64) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetRefundWindow { refund_window },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_vesting(admin: &Pubkey, vesting: VestingSchedule) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetVesting { vesting },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn open_vesting(user: &Pubkey, mint: &Pubkey) -> Instruction {
+        let (vesting_pubkey, _) = Vesting::get_vesting_pubkey(user);
+        let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(user);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::OpenVesting,
+            vec![
+                AccountMeta::new(*user, true),
+                AccountMeta::new(vesting_pubkey, false),
+                AccountMeta::new(escrow_pubkey, false),
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+
+    pub fn claim(user: &Pubkey, user_token_account: &Pubkey) -> Instruction {
+        let (vesting_pubkey, _) = Vesting::get_vesting_pubkey(user);
+        let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(user);
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Claim,
+            vec![
+                AccountMeta::new_readonly(*user, true),
+                AccountMeta::new(*user_token_account, false),
+                AccountMeta::new(vesting_pubkey, false),
+                AccountMeta::new(escrow_pubkey, false),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+
+    pub fn set_inventory(admin: &Pubkey, inventory: Inventory) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetInventory { inventory },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_quote(admin: &Pubkey, quote: Quote) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetQuote { quote },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn create_price_history(payer: &Pubkey) -> Instruction {
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::CreatePriceHistory,
+            vec![
+                AccountMeta::new(*payer, true),
+                AccountMeta::new(history_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn set_history_mode(admin: &Pubkey, history: HistoryMode) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetHistoryMode { history },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+                AccountMeta::new_readonly(history_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_limits(admin: &Pubkey, limits: TradeLimits) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetLimits { limits },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn queue_settings_change(admin: &Pubkey, change: SettingsChange) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::QueueSettingsChange { change },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    /// `product_id` is the product whose price the queued change sets, if any.
+    pub fn execute_settings_change(product_id: Option<u64>) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+        let mut accounts = vec![AccountMeta::new(settings_pubkey, false)];
+        if let Some(product_id) = product_id {
+            let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+            accounts.push(AccountMeta::new(product_pubkey, false));
+        }
+        accounts.push(AccountMeta::new(history_pubkey, false));
+        Instruction::new_with_borsh(id(), &StoreInstruction::ExecuteSettingsChange, accounts)
+    }
+
+    pub fn add_product(
+        payer: &Pubkey,
+        admin: &Pubkey,
+        product_id: u64,
+        mint: &Pubkey,
+        price: u32,
+        stock: u64,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::AddProduct {
+                product_id,
+                mint: mint.to_bytes(),
+                price,
+                stock,
+            },
+            vec![
+                AccountMeta::new(*payer, true),
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new(product_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn update_product(
+        admin: &Pubkey,
+        product_id: u64,
+        price: u32,
+        stock: u64,
+        active: bool,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::UpdateProduct {
+                price,
+                stock,
+                active,
+            },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new(product_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn remove_product(admin: &Pubkey, product_id: u64, recipient: &Pubkey) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let (product_pubkey, _) = Product::get_product_pubkey(product_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::RemoveProduct,
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new(product_pubkey, false),
+                AccountMeta::new(*recipient, false),
+            ],
+        )
+    }
+
+    /// `accounts` holds the (store token account, user token account) pair of
+    /// each item, in cart order.
+    pub fn checkout(user: &Pubkey, items: &[CartItem], accounts: &[(Pubkey, Pubkey)]) -> Instruction {
+        let (store_pubkey, _) = Store::get_store_pubkey();
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut metas = vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(store_pubkey, false),
+            AccountMeta::new(settings_pubkey, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        for (item, (store_token_account, user_token_account)) in items.iter().zip(accounts) {
+            let (product_pubkey, _) = Product::get_product_pubkey(item.product_id);
+            metas.extend(vec![
+                AccountMeta::new(product_pubkey, false),
+                AccountMeta::new(*store_token_account, false),
+                AccountMeta::new(*user_token_account, false),
+            ]);
+        }
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Checkout {
+                items: items.to_vec(),
+            },
+            metas,
+        )
+    }
+
+    pub fn list_tokens(
+        seller: &Pubkey,
+        seller_token_account: &Pubkey,
+        escrow_token_account: &Pubkey,
+        listing_id: u64,
+        price: u32,
+        amount: u64,
+    ) -> Instruction {
+        let (listing_pubkey, _) = Listing::get_listing_pubkey(seller, listing_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ListTokens {
+                listing_id,
+                price,
+                amount,
+            },
+            vec![
+                AccountMeta::new(*seller, true),
+                AccountMeta::new(*seller_token_account, false),
+                AccountMeta::new(listing_pubkey, false),
+                AccountMeta::new(*escrow_token_account, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+
+    pub fn delist(listing: &Listing, seller_token_account: &Pubkey) -> Instruction {
+        let seller = Pubkey::new_from_array(listing.seller);
+        let (listing_pubkey, _) = Listing::get_listing_pubkey(&seller, listing.listing_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Delist,
+            vec![
+                AccountMeta::new(seller, true),
+                AccountMeta::new(listing_pubkey, false),
+                AccountMeta::new(Pubkey::new_from_array(listing.escrow), false),
+                AccountMeta::new(*seller_token_account, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        )
+    }
+
+    pub fn set_commission_bps(admin: &Pubkey, commission_bps: u16) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetCommissionBps { commission_bps },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn set_subscription_terms(admin: &Pubkey, terms: SubscriptionTerms) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetSubscriptionTerms { terms },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn subscribe(
+        subscriber: &Pubkey,
+        deliver_to: &Pubkey,
+        payment_account: Option<&Pubkey>,
+        deposit: u64,
+    ) -> Instruction {
+        let (subscription_pubkey, _) = Subscription::get_subscription_pubkey(subscriber);
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new(*subscriber, true),
+            AccountMeta::new(subscription_pubkey, false),
+            AccountMeta::new_readonly(settings_pubkey, false),
+            AccountMeta::new_readonly(*deliver_to, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        let funding = match payment_account {
+            Some(payment_account) => {
+                accounts.push(AccountMeta::new_readonly(*payment_account, false));
+                SubscriptionFunding::Delegate
+            }
+            None => SubscriptionFunding::Prefunded,
+        };
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::Subscribe { funding, deposit },
+            accounts,
+        )
+    }
+
+    /// `store_payment_account` is needed for delegated subscriptions and
+    /// `store_token_account` while `SubscriptionTerms.delivery_amount` is set.
+    pub fn charge_subscription(
+        subscription: &Subscription,
+        store_payment_account: Option<&Pubkey>,
+        store_token_account: Option<&Pubkey>,
+    ) -> Instruction {
+        let subscriber = Pubkey::new_from_array(subscription.subscriber);
+        let (subscription_pubkey, _) = Subscription::get_subscription_pubkey(&subscriber);
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new(subscription_pubkey, false),
+            AccountMeta::new(Store::get_store_pub(), false),
+            AccountMeta::new_readonly(settings_pubkey, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ];
+        if let Some(store_payment_account) = store_payment_account {
+            accounts.push(AccountMeta::new(
+                Pubkey::new_from_array(subscription.payment_account),
+                false,
+            ));
+            accounts.push(AccountMeta::new(*store_payment_account, false));
+        }
+        if let Some(store_token_account) = store_token_account {
+            accounts.push(AccountMeta::new(*store_token_account, false));
+            accounts.push(AccountMeta::new(
+                Pubkey::new_from_array(subscription.deliver_to),
+                false,
+            ));
+        }
+        Instruction::new_with_borsh(id(), &StoreInstruction::ChargeSubscription, accounts)
+    }
+
+    pub fn cancel_subscription(subscriber: &Pubkey) -> Instruction {
+        let (subscription_pubkey, _) = Subscription::get_subscription_pubkey(subscriber);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::CancelSubscription,
+            vec![
+                AccountMeta::new(*subscriber, true),
+                AccountMeta::new(subscription_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn create_invoice(
+        merchant: &Pubkey,
+        invoice_id: u64,
+        amount: u64,
+        mint: Option<&Pubkey>,
+        memo: String,
+        expiry: i64,
+    ) -> Instruction {
+        let (invoice_pubkey, _) = Invoice::get_invoice_pubkey(merchant, invoice_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::CreateInvoice {
+                invoice_id,
+                amount,
+                mint: mint.map_or([0; 32], |mint| mint.to_bytes()),
+                memo,
+                expiry,
+            },
+            vec![
+                AccountMeta::new(*merchant, true),
+                AccountMeta::new(invoice_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    /// Pay a lamport invoice in native SOL
+    pub fn pay_invoice(payer: &Pubkey, invoice: &Invoice) -> Instruction {
+        let merchant = Pubkey::new_from_array(invoice.merchant);
+        let accounts = Self::pay_invoice_accounts(payer, invoice, &merchant);
+        Instruction::new_with_borsh(id(), &StoreInstruction::PayInvoice, accounts)
+    }
+
+    /// Pay a lamport invoice from the payer's wSOL token account, for stores
+    /// quoted in wrapped SOL
+    pub fn pay_invoice_with_wsol(
+        payer: &Pubkey,
+        invoice: &Invoice,
+        payer_wsol_account: &Pubkey,
+    ) -> Instruction {
+        let merchant = Pubkey::new_from_array(invoice.merchant);
+        let (wsol_pubkey, _) = Store::get_wsol_pubkey(payer);
+        let mut accounts = Self::pay_invoice_accounts(payer, invoice, &merchant);
+        accounts.extend(vec![
+            AccountMeta::new(*payer_wsol_account, false),
+            AccountMeta::new(wsol_pubkey, false),
+            AccountMeta::new_readonly(spl_token::native_mint::id(), false),
+        ]);
+        Instruction::new_with_borsh(id(), &StoreInstruction::PayInvoice, accounts)
+    }
+
+    /// Pay a token invoice. The commission goes to `store_token_account`,
+    /// owned by the store PDA.
+    pub fn pay_invoice_with_token(
+        payer: &Pubkey,
+        invoice: &Invoice,
+        merchant_token_account: &Pubkey,
+        payer_token_account: &Pubkey,
+        store_token_account: &Pubkey,
+    ) -> Instruction {
+        let mut accounts = Self::pay_invoice_accounts(payer, invoice, merchant_token_account);
+        accounts.extend(vec![
+            AccountMeta::new(*payer_token_account, false),
+            AccountMeta::new(*store_token_account, false),
+        ]);
+        Instruction::new_with_borsh(id(), &StoreInstruction::PayInvoice, accounts)
+    }
+
+    fn pay_invoice_accounts(
+        payer: &Pubkey,
+        invoice: &Invoice,
+        recipient: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        let merchant = Pubkey::new_from_array(invoice.merchant);
+        let (invoice_pubkey, _) = Invoice::get_invoice_pubkey(&merchant, invoice.invoice_id);
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(invoice_pubkey, false),
+            AccountMeta::new(*recipient, false),
+            AccountMeta::new(Store::get_store_pub(), false),
+            AccountMeta::new_readonly(settings_pubkey, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ]
+    }
+
+    pub fn expire_invoice(merchant: &Pubkey, invoice_id: u64) -> Instruction {
+        let (invoice_pubkey, _) = Invoice::get_invoice_pubkey(merchant, invoice_id);
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ExpireInvoice,
+            vec![AccountMeta::new(invoice_pubkey, false)],
+        )
+    }
+
+    pub fn set_escrow_timeout(admin: &Pubkey, escrow_timeout: i64) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetEscrowTimeout { escrow_timeout },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn open_escrow(
+        buyer: &Pubkey,
+        seller: &Pubkey,
+        escrow_id: u64,
+        lamports: u64,
+    ) -> Instruction {
+        let (escrow_pubkey, _) = Escrow::get_escrow_pubkey(buyer, escrow_id);
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::OpenEscrow {
+                escrow_id,
+                lamports,
+            },
+            vec![
+                AccountMeta::new(*buyer, true),
+                AccountMeta::new(escrow_pubkey, false),
+                AccountMeta::new_readonly(*seller, false),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new_readonly(sysvar::rent::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        )
+    }
+
+    pub fn confirm_delivery(escrow: &Escrow) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ConfirmDelivery,
+            vec![
+                AccountMeta::new_readonly(Pubkey::new_from_array(escrow.seller), true),
+                AccountMeta::new(Self::escrow_pubkey(escrow), false),
+                AccountMeta::new_readonly(settings_pubkey, false),
+            ],
+        )
+    }
+
+    /// `caller` is the buyer, or anyone once the release timeout has passed
+    pub fn release_escrow(caller: &Pubkey, escrow: &Escrow) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ReleaseEscrow,
+            vec![
+                AccountMeta::new_readonly(*caller, true),
+                AccountMeta::new(Self::escrow_pubkey(escrow), false),
+                AccountMeta::new(Pubkey::new_from_array(escrow.buyer), false),
+                AccountMeta::new(Pubkey::new_from_array(escrow.seller), false),
+                AccountMeta::new(Store::get_store_pub(), false),
+                AccountMeta::new_readonly(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn open_dispute(party: &Pubkey, escrow: &Escrow) -> Instruction {
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::OpenDispute,
+            vec![
+                AccountMeta::new_readonly(*party, true),
+                AccountMeta::new(Self::escrow_pubkey(escrow), false),
+            ],
+        )
+    }
+
+    pub fn resolve_dispute(admin: &Pubkey, escrow: &Escrow, buyer_bps: u16) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::ResolveDispute { buyer_bps },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new_readonly(settings_pubkey, false),
+                AccountMeta::new(Self::escrow_pubkey(escrow), false),
+                AccountMeta::new(Pubkey::new_from_array(escrow.buyer), false),
+                AccountMeta::new(Pubkey::new_from_array(escrow.seller), false),
+                AccountMeta::new(Store::get_store_pub(), false),
+            ],
+        )
+    }
+
+    fn escrow_pubkey(escrow: &Escrow) -> Pubkey {
+        let buyer = Pubkey::new_from_array(escrow.buyer);
+        let (escrow_pubkey, _) = Escrow::get_escrow_pubkey(&buyer, escrow.escrow_id);
+        escrow_pubkey
+    }
+
+    pub fn set_revenue_split(admin: &Pubkey, shares: Vec<RevenueShare>) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::SetRevenueSplit { shares },
+            vec![
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+            ],
+        )
+    }
+
+    pub fn distribute(
+        treasurer: &Pubkey,
+        signers: &[&Pubkey],
+        beneficiaries: &[Pubkey],
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*treasurer, signers.is_empty()),
+            AccountMeta::new_readonly(settings_pubkey, false),
+            AccountMeta::new(Store::get_store_pub(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        accounts.extend(
+            beneficiaries
+                .iter()
+                .map(|beneficiary| AccountMeta::new(*beneficiary, false)),
+        );
+        accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(**signer, true)));
+        Instruction::new_with_borsh(id(), &StoreInstruction::Distribute, accounts)
+    }
+
+    pub fn migrate_settings(
+        payer: &Pubkey,
+        admin: &Pubkey,
+        store_mint: &Pubkey,
+        store_vault: &Pubkey,
+    ) -> Instruction {
+        let (settings_pubkey, _) = Settings::get_settings_pubkey();
+        Instruction::new_with_borsh(
+            id(),
+            &StoreInstruction::MigrateSettings,
+            vec![
+                AccountMeta::new(*payer, true),
+                AccountMeta::new_readonly(*admin, true),
+                AccountMeta::new(settings_pubkey, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(*store_mint, false),
+                AccountMeta::new_readonly(*store_vault, false),
+            ],
         )
     }
 }
@@ -3,8 +3,25 @@ pub mod processor;
 pub mod instruction;
 pub mod state;
 pub mod error;
+pub mod events;
+pub mod voucher;
 
 pub const PRICE_SEED: &str = "price";
 pub const SETTINGS_SEED: &str = "settings";
+pub const STORE_SEED: &str = "store";
+pub const MULTISIG_SEED: &str = "multisig";
+pub const REFERRAL_SEED: &str = "referral";
+pub const VOUCHER_SEED: &str = "voucher";
+pub const ORDER_SEED: &str = "order";
+pub const RECEIPT_SEED: &str = "receipt";
+pub const VESTING_SEED: &str = "vesting";
+pub const VESTING_ESCROW_SEED: &str = "vesting_escrow";
+pub const WSOL_SEED: &str = "wsol";
+pub const PRICE_HISTORY_SEED: &str = "price_history";
+pub const PRODUCT_SEED: &str = "product";
+pub const LISTING_SEED: &str = "listing";
+pub const SUBSCRIPTION_SEED: &str = "subscription";
+pub const INVOICE_SEED: &str = "invoice";
+pub const ESCROW_SEED: &str = "escrow";
 
 solana_program::declare_id!("uMv1Gq62jXMHEZFy2YyqM7jP5hYV8QDfMP4kXJFNxZG");
@@ -1,17 +1,32 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    instruction::Instruction,
     entrypoint::ProgramResult,
     msg,
     program::{invoke_signed, invoke},
+    program_option::COption,
+    program_pack::Pack,
     pubkey::Pubkey,
     system_instruction,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, instructions, rent::Rent, Sysvar},
     program_error::ProgramError
 };
 
-use crate::{id, SETTINGS_SEED};
-use crate::{instruction::StoreInstruction, state::Price, state::Settings, error::PriceError};
+use crate::{
+    id, MULTISIG_SEED, ORDER_SEED, RECEIPT_SEED, REFERRAL_SEED, SETTINGS_SEED, STORE_SEED,
+    VESTING_ESCROW_SEED, VESTING_SEED, VOUCHER_SEED, WSOL_SEED, PRICE_HISTORY_SEED, PRODUCT_SEED,
+    LISTING_SEED, SUBSCRIPTION_SEED, INVOICE_SEED, ESCROW_SEED,
+};
+use crate::{instruction::StoreInstruction, state::Price, state::Settings, state::Store, error::PriceError, error::TokenError};
+use crate::state::{
+    BuySource, CartItem, DutchAuction, Escrow, EscrowStatus, HistoryMode, Inventory, Invoice, InvoiceStatus, Listing, Multisig, Order, PriceEntry, PriceHistory, PriceMode, Product,
+    Quote, Receipt, Referral, RevenueShare, RevenueSplit, SettingsChange, Side, Subscription, SubscriptionFunding,
+    SubscriptionTerms, TradeLimits, Vesting, VestingSchedule,
+    VoucherNonce, MAX_BPS, MAX_MEMO_LEN,
+};
+use crate::voucher::Voucher;
+use crate::events::StoreEvent;
 
 
 
@@ -19,7 +34,6 @@ pub struct Processor;
 
 impl Processor {
     pub fn process(_program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        msg!("price: {:?}", input);
         let instruction = StoreInstruction::try_from_slice(input)?;
         match instruction {
             StoreInstruction::InitializeStore => Self::process_initialize_store(accounts),
@@ -27,13 +41,151 @@ impl Processor {
                 admin,
                 updated_price,
             } => Self::process_update_settings(accounts, admin, updated_price),
-            StoreInstruction::Buy { amount } => Self::process_buy(accounts),
-            StoreInstruction::Sell { amount } => Self::process_sell(accounts)
+            StoreInstruction::Buy {
+                amount,
+                receipt_id,
+                source,
+                allow_partial,
+                create_associated,
+            } => Self::process_buy(
+                accounts,
+                amount,
+                receipt_id,
+                source,
+                allow_partial,
+                create_associated,
+                None,
+            ),
+            StoreInstruction::Sell { amount } => Self::process_sell(accounts, amount),
+            StoreInstruction::SellDelegated { amount } => {
+                Self::process_sell_delegated(accounts, amount)
+            }
+            StoreInstruction::CreateMultisig { threshold, signers } => {
+                Self::process_create_multisig(accounts, threshold, signers)
+            }
+            StoreInstruction::UpdatePrice { updated_price } => {
+                Self::process_update_price(accounts, updated_price)
+            }
+            StoreInstruction::SetPaused { paused } => Self::process_set_paused(accounts, paused),
+            StoreInstruction::SetRoles {
+                price_manager,
+                treasurer,
+                pauser,
+            } => Self::process_set_roles(accounts, price_manager, treasurer, pauser),
+            StoreInstruction::Withdraw { lamports } => Self::process_withdraw(accounts, lamports),
+            StoreInstruction::SetReferralBps { referral_bps } => {
+                Self::process_set_referral_bps(accounts, referral_bps)
+            }
+            StoreInstruction::RegisterReferrer => Self::process_register_referrer(accounts),
+            StoreInstruction::ClaimReferral => Self::process_claim_referral(accounts),
+            StoreInstruction::BuyWithVoucher {
+                amount,
+                receipt_id,
+                voucher,
+                create_associated,
+            } => Self::process_buy(
+                accounts,
+                amount,
+                receipt_id,
+                BuySource::Store,
+                false,
+                create_associated,
+                Some(voucher),
+            ),
+            StoreInstruction::PlaceOrder {
+                order_id,
+                side,
+                price,
+                amount,
+            } => Self::process_place_order(accounts, order_id, side, price, amount),
+            StoreInstruction::CancelOrder => Self::process_cancel_order(accounts),
+            StoreInstruction::FillOrder { amount } => Self::process_fill_order(accounts, amount),
+            StoreInstruction::StartDutchAuction { auction } => {
+                Self::process_start_dutch_auction(accounts, auction)
+            }
+            StoreInstruction::SetRefundWindow { refund_window } => {
+                Self::process_set_refund_window(accounts, refund_window)
+            }
+            StoreInstruction::Refund => Self::process_refund(accounts),
+            StoreInstruction::SetVesting { vesting } => Self::process_set_vesting(accounts, vesting),
+            StoreInstruction::OpenVesting => Self::process_open_vesting(accounts),
+            StoreInstruction::Claim => Self::process_claim(accounts),
+            StoreInstruction::SetInventory { inventory } => {
+                Self::process_set_inventory(accounts, inventory)
+            }
+            StoreInstruction::SetQuote { quote } => Self::process_set_quote(accounts, quote),
+            StoreInstruction::CreatePriceHistory => Self::process_create_price_history(accounts),
+            StoreInstruction::SetHistoryMode { history } => {
+                Self::process_set_history_mode(accounts, history)
+            }
+            StoreInstruction::SetLimits { limits } => Self::process_set_limits(accounts, limits),
+            StoreInstruction::QueueSettingsChange { change } => {
+                Self::process_queue_settings_change(accounts, change)
+            }
+            StoreInstruction::ExecuteSettingsChange => {
+                Self::process_execute_settings_change(accounts)
+            }
+            StoreInstruction::AddProduct {
+                product_id,
+                mint,
+                price,
+                stock,
+            } => Self::process_add_product(accounts, product_id, mint, price, stock),
+            StoreInstruction::UpdateProduct {
+                price,
+                stock,
+                active,
+            } => Self::process_update_product(accounts, price, stock, active),
+            StoreInstruction::RemoveProduct => Self::process_remove_product(accounts),
+            StoreInstruction::Checkout { items } => Self::process_checkout(accounts, items),
+            StoreInstruction::ListTokens {
+                listing_id,
+                price,
+                amount,
+            } => Self::process_list_tokens(accounts, listing_id, price, amount),
+            StoreInstruction::Delist => Self::process_delist(accounts),
+            StoreInstruction::SetCommissionBps { commission_bps } => {
+                Self::process_set_commission_bps(accounts, commission_bps)
+            }
+            StoreInstruction::SetSubscriptionTerms { terms } => {
+                Self::process_set_subscription_terms(accounts, terms)
+            }
+            StoreInstruction::Subscribe { funding, deposit } => {
+                Self::process_subscribe(accounts, funding, deposit)
+            }
+            StoreInstruction::ChargeSubscription => Self::process_charge_subscription(accounts),
+            StoreInstruction::CancelSubscription => Self::process_cancel_subscription(accounts),
+            StoreInstruction::CreateInvoice {
+                invoice_id,
+                amount,
+                mint,
+                memo,
+                expiry,
+            } => Self::process_create_invoice(accounts, invoice_id, amount, mint, memo, expiry),
+            StoreInstruction::PayInvoice => Self::process_pay_invoice(accounts),
+            StoreInstruction::ExpireInvoice => Self::process_expire_invoice(accounts),
+            StoreInstruction::SetEscrowTimeout { escrow_timeout } => {
+                Self::process_set_escrow_timeout(accounts, escrow_timeout)
+            }
+            StoreInstruction::OpenEscrow {
+                escrow_id,
+                lamports,
+            } => Self::process_open_escrow(accounts, escrow_id, lamports),
+            StoreInstruction::ConfirmDelivery => Self::process_confirm_delivery(accounts),
+            StoreInstruction::ReleaseEscrow => Self::process_release_escrow(accounts),
+            StoreInstruction::OpenDispute => Self::process_open_dispute(accounts),
+            StoreInstruction::ResolveDispute { buyer_bps } => {
+                Self::process_resolve_dispute(accounts, buyer_bps)
+            }
+            StoreInstruction::SetRevenueSplit { shares } => {
+                Self::process_set_revenue_split(accounts, shares)
+            }
+            StoreInstruction::Distribute => Self::process_distribute(accounts),
+            StoreInstruction::MigrateSettings => Self::process_migrate_settings(accounts),
         }
     }
 
     fn process_initialize_store(accounts: &[AccountInfo]) -> ProgramResult {
-        msg!("process_price");
         let acc_iter = &mut accounts.iter();
         let user_info = next_account_info(acc_iter)?;
         let price_info = next_account_info(acc_iter)?;
@@ -55,9 +207,13 @@ impl Processor {
         price.counter += 1;
         price.value += 15;
 
-        msg!("price is {:?}", price.value);
-
         let _ = price.serialize(&mut &mut price_info.data.borrow_mut()[..]);
+        StoreEvent::StoreInitialized {
+            user: user_info.key.to_bytes(),
+            counter: price.counter,
+            value: price.value,
+        }
+        .emit();
 
         Ok(())
     }
@@ -67,11 +223,6 @@ impl Processor {
         admin: [u8; 32],
         updated_price: u32,
     ) -> ProgramResult {
-        msg!(
-            "process_update_settings: admin={:?} updated_price={:?}",
-            admin,
-            updated_price,
-        );
         let acc_iter = &mut accounts.iter();
         let admin_info = next_account_info(acc_iter)?;
         let settings_info = next_account_info(acc_iter)?;
@@ -79,12 +230,10 @@ impl Processor {
         let system_program_info = next_account_info(acc_iter)?;
 
         let (settings_pubkey, bump_seed) = Settings::get_settings_pubkey();
-        if settings_info.data_is_empty() {
+        let mut history_info = None;
+        let mut settings = if settings_info.data_is_empty() {
             msg!("Creating settings account");
-            let settings = Settings {
-                admin: admin_info.key.to_bytes(),
-                updated_price,
-            };
+            let settings = Settings::new(admin_info.key.to_bytes(), updated_price);
             let space = settings.try_to_vec()?.len();
             let rent = &Rent::from_account_info(rent_info)?;
             let lamports = rent.minimum_balance(space);
@@ -104,98 +253,3560 @@ impl Processor {
                 ],
                 &[&signer_seeds],
             )?;
-        }
+            // Every role starts with the admin
+            settings
+        } else {
+            let settings = Settings::try_from_slice(&settings_info.data.borrow())?;
+            if settings.timelock_delay != 0 {
+                return Err(PriceError::TimelockActive.into());
+            }
+            history_info = Self::next_price_history(&settings, HistoryMode::PriceChanges, acc_iter)?;
+            Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+            settings
+        };
 
-        let mut settings = Settings::try_from_slice(&settings_info.data.borrow())?;
+        let price_changed = settings.updated_price != updated_price;
         settings.admin = admin;
         settings.updated_price = updated_price;
+        settings.price_mode = PriceMode::Fixed;
+        if let (Some(history_info), true) = (history_info, price_changed) {
+            Self::record_price(history_info, updated_price)?;
+        }
 
-        let _ = settings.serialize(&mut &mut settings_info.data.borrow_mut()[..]);
-        msg!("process_update_settings: done");
+        Self::save_settings(settings_info, &settings)?;
         Ok(())
     }
 
-    fn process_buy(
+    fn process_create_multisig(
         accounts: &[AccountInfo],
+        threshold: u8,
+        signers: Vec<[u8; 32]>,
     ) -> ProgramResult {
         let acc_iter = &mut accounts.iter();
-        let store_account = next_account_info(acc_iter)?;
-        let store_token_account = next_account_info(acc_iter)?;
-        let user_account = next_account_info(acc_iter)?;
-        let user_token_account = next_account_info(acc_iter)?;
-        let token_mint_account = next_account_info(acc_iter)?;
-        let spl_token_id = next_account_info(acc_iter)?;
-        msg!("accounts {:#?}", accounts);
+        let creator_info = next_account_info(acc_iter)?;
+        let multisig_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !creator_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let (multisig_pubkey, bump_seed) = Multisig::get_multisig_pubkey(creator_info.key);
+        if multisig_pubkey != *multisig_info.key {
+            return Err(PriceError::WrongMultisigPDA.into());
+        }
+        let multisig = Multisig { threshold, signers };
+        if !multisig.is_valid() {
+            return Err(PriceError::InvalidMultisig.into());
+        }
 
-        let ix = spl_token::instruction::transfer(
-            spl_token_id.key,
-            store_token_account.key,
-            user_token_account.key,
-            store_account.key,
-            &[store_account.key],
-            10,
+        let space = multisig.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let lamports = rent.minimum_balance(space);
+        let signer_seeds: &[&[_]] = &[
+            MULTISIG_SEED.as_bytes(),
+            &creator_info.key.to_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                creator_info.key,
+                &multisig_pubkey,
+                lamports,
+                space as u64,
+                &id(),
+            ),
+            &[
+                creator_info.clone(),
+                multisig_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
         )?;
-        invoke(
-            &ix,
+
+        multisig.serialize(&mut &mut multisig_info.data.borrow_mut()[..])?;
+        StoreEvent::MultisigCreated {
+            multisig: multisig_pubkey.to_bytes(),
+            threshold: multisig.threshold,
+            signers: multisig.signers,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Checks that `authority_info` is the `expected` key and has signed. If it is
+    /// a multisig account instead, at least `threshold` of its keys must be among
+    /// the signed `signer_infos`.
+    fn validate_authority(
+        expected: &[u8; 32],
+        authority_info: &AccountInfo,
+        signer_infos: &[AccountInfo],
+    ) -> ProgramResult {
+        if authority_info.key.to_bytes() != *expected {
+            return Err(PriceError::AdminRequired.into());
+        }
+        if authority_info.owner == &id() && !authority_info.data_is_empty() {
+            let multisig = Multisig::try_from_slice(&authority_info.data.borrow())?;
+            let signed: Vec<Pubkey> = signer_infos
+                .iter()
+                .filter(|info| info.is_signer)
+                .map(|info| *info.key)
+                .collect();
+            if multisig.count_signers(&signed) < multisig.threshold {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            return Ok(());
+        }
+        if !authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    fn process_update_price(accounts: &[AccountInfo], updated_price: u32) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let price_manager_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.timelock_delay != 0 {
+            return Err(PriceError::TimelockActive.into());
+        }
+        let history_info =
+            Self::next_price_history(&settings, HistoryMode::PriceChanges, acc_iter)?;
+        Self::validate_authority(&settings.price_manager, price_manager_info, acc_iter.as_slice())?;
+        if let (Some(history_info), true) = (history_info, settings.updated_price != updated_price) {
+            Self::record_price(history_info, updated_price)?;
+        }
+        settings.updated_price = updated_price;
+        settings.price_mode = PriceMode::Fixed;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_queue_settings_change(
+        accounts: &[AccountInfo],
+        change: SettingsChange,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if change.timelock_delay < 0 {
+            return Err(PriceError::InvalidInstruction.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        let eta = Clock::get()?
+            .unix_timestamp
+            .checked_add(settings.timelock_delay)
+            .ok_or(PriceError::Overflow)?;
+        settings.pending = change;
+        settings.pending_eta = eta;
+        Self::save_settings(settings_info, &settings)?;
+        StoreEvent::SettingsChangeQueued { change, eta }.emit();
+        Ok(())
+    }
+
+    fn process_execute_settings_change(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.pending_eta == 0 {
+            return Err(PriceError::NoPendingChange.into());
+        }
+        if Clock::get()?.unix_timestamp < settings.pending_eta {
+            return Err(PriceError::TimelockNotElapsed.into());
+        }
+        let change = settings.pending;
+        if change.product != [0; 32] {
+            let product_info = next_account_info(acc_iter)?;
+            if product_info.key.to_bytes() != change.product || product_info.owner != &id() {
+                return Err(PriceError::WrongProductPDA.into());
+            }
+            let mut product = Product::try_from_slice(&product_info.data.borrow())?;
+            if !Product::is_pubkey_ok(product.product_id, product_info.key) {
+                return Err(PriceError::WrongProductPDA.into());
+            }
+            product.price = change.product_price;
+            product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+            StoreEvent::ProductUpdated { product }.emit();
+        }
+        if change.updated_price != settings.updated_price {
+            if let Some(history_info) =
+                Self::next_price_history(&settings, HistoryMode::PriceChanges, acc_iter)?
+            {
+                Self::record_price(history_info, change.updated_price)?;
+            }
+        }
+        settings.admin = change.admin;
+        settings.updated_price = change.updated_price;
+        settings.price_mode = PriceMode::Fixed;
+        settings.timelock_delay = change.timelock_delay;
+        settings.pending = SettingsChange::default();
+        settings.pending_eta = 0;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    /// The price history account, read next when `settings` records at least `mode`.
+    fn next_price_history<'a, 'b>(
+        settings: &Settings,
+        mode: HistoryMode,
+        acc_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+    ) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+        let records = match mode {
+            HistoryMode::Off => false,
+            HistoryMode::PriceChanges => settings.history != HistoryMode::Off,
+            HistoryMode::PriceChangesAndTrades => {
+                settings.history == HistoryMode::PriceChangesAndTrades
+            }
+        };
+        if !records {
+            return Ok(None);
+        }
+        let history_info = next_account_info(acc_iter)?;
+        if history_info.owner != &id() || !PriceHistory::is_pubkey_ok(history_info.key) {
+            return Err(PriceError::WrongPriceHistoryPDA.into());
+        }
+        Ok(Some(history_info))
+    }
+
+    fn record_price(history_info: &AccountInfo, price: u32) -> ProgramResult {
+        let mut history = PriceHistory::try_from_slice(&history_info.data.borrow())?;
+        let clock = Clock::get()?;
+        history.push(PriceEntry {
+            slot: clock.slot,
+            timestamp: clock.unix_timestamp,
+            price,
+        });
+        history.serialize(&mut &mut history_info.data.borrow_mut()[..])?;
+        Ok(())
+    }
+
+    fn process_create_price_history(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let payer_info = next_account_info(acc_iter)?;
+        let history_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let (history_pubkey, bump_seed) = PriceHistory::get_history_pubkey();
+        if history_pubkey != *history_info.key {
+            return Err(PriceError::WrongPriceHistoryPDA.into());
+        }
+        let history = PriceHistory::default();
+        let space = history.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let signer_seeds: &[&[_]] = &[PRICE_HISTORY_SEED.as_bytes(), &[bump_seed]];
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                &history_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
             &[
-                spl_token_id.clone(),
-                store_token_account.clone(),
-                token_mint_account.clone(),
-                user_token_account.clone(),
-                store_account.clone(),
+                payer_info.clone(),
+                history_info.clone(),
+                system_program_info.clone(),
             ],
+            &[signer_seeds],
         )?;
-        let ixs = system_instruction::transfer(
-            user_account.key,
-            store_account.key,
-            10,
-        );
+        history.serialize(&mut &mut history_info.data.borrow_mut()[..])?;
+        StoreEvent::PriceHistoryCreated {
+            history: history_pubkey.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
 
-        invoke(
-            &ixs,
-            &[user_account.clone(), store_account.clone()]
-        );
+    fn process_set_history_mode(accounts: &[AccountInfo], history: HistoryMode) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let history_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        if history != HistoryMode::Off
+            && (history_info.owner != &id() || !PriceHistory::is_pubkey_ok(history_info.key))
+        {
+            return Err(PriceError::WrongPriceHistoryPDA.into());
+        }
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.history = history;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_start_dutch_auction(accounts: &[AccountInfo], auction: DutchAuction) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let price_manager_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if !auction.is_valid() {
+            return Err(PriceError::InvalidAuction.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.timelock_delay != 0 {
+            return Err(PriceError::TimelockActive.into());
+        }
+        Self::validate_authority(&settings.price_manager, price_manager_info, acc_iter.as_slice())?;
+        settings.price_mode = PriceMode::DutchAuction;
+        settings.auction = auction;
+        Self::save_settings(settings_info, &settings)?;
         Ok(())
     }
-    fn process_sell(
-        accounts: &[AccountInfo]
+
+    fn process_set_paused(accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let pauser_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.pauser, pauser_info, acc_iter.as_slice())?;
+        settings.paused = paused;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_set_roles(
+        accounts: &[AccountInfo],
+        price_manager: [u8; 32],
+        treasurer: [u8; 32],
+        pauser: [u8; 32],
     ) -> ProgramResult {
         let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.price_manager = price_manager;
+        settings.treasurer = treasurer;
+        settings.pauser = pauser;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_withdraw(accounts: &[AccountInfo], lamports: u64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let treasurer_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
         let store_info = next_account_info(acc_iter)?;
-        let store_token_info = next_account_info(acc_iter)?;
+        let recipient_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        let settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.treasurer, treasurer_info, acc_iter.as_slice())?;
+        let store_bump = Self::check_store(store_info)?;
+        // The store PDA stays rent exempt
+        let available = store_info
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(store_info.data_len()));
+        let lamports = lamports.min(available);
+
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        invoke_signed(
+            &system_instruction::transfer(store_info.key, recipient_info.key, lamports),
+            &[
+                store_info.clone(),
+                recipient_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        StoreEvent::Withdrawn {
+            recipient: recipient_info.key.to_bytes(),
+            lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_migrate_settings(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let payer_info = next_account_info(acc_iter)?;
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let store_mint_info = next_account_info(acc_iter)?;
+        let store_vault_info = next_account_info(acc_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if settings_info.owner != &id() || !Settings::is_pubkey_ok(settings_info.key) {
+            return Err(PriceError::WrongSettingsPDA.into());
+        }
+        let mut settings = Settings::migrate(&settings_info.data.borrow())
+            .ok_or(ProgramError::InvalidAccountData)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+
+        if store_mint_info.owner != &spl_token::id() || store_vault_info.owner != &spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        spl_token::state::Mint::unpack(&store_mint_info.data.borrow())?;
+        // A store that only mints on demand has no vault and passes the mint twice
+        if store_vault_info.key != store_mint_info.key {
+            let store_vault = spl_token::state::Account::unpack(&store_vault_info.data.borrow())?;
+            if store_vault.owner != Store::get_store_pub() {
+                return Err(TokenError::NoOwner.into());
+            }
+            if store_vault.mint != *store_mint_info.key {
+                return Err(TokenError::TokenMismatch.into());
+            }
+        }
+        let old = settings.clone();
+        settings.store_mint = store_mint_info.key.to_bytes();
+        settings.store_vault = store_vault_info.key.to_bytes();
+
+        let space = settings.try_to_vec()?.len();
+        let rent_due = Rent::get()?
+            .minimum_balance(space)
+            .saturating_sub(settings_info.lamports());
+        if rent_due > 0 {
+            invoke(
+                &system_instruction::transfer(payer_info.key, settings_info.key, rent_due),
+                &[
+                    payer_info.clone(),
+                    settings_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        settings_info.realloc(space, false)?;
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        StoreEvent::settings_updated(&old, &settings).emit();
+        Ok(())
+    }
+
+    fn process_set_referral_bps(accounts: &[AccountInfo], referral_bps: u16) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if referral_bps > MAX_BPS {
+            return Err(PriceError::InvalidBps.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.referral_bps = referral_bps;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_register_referrer(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let referrer_info = next_account_info(acc_iter)?;
+        let referral_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !referrer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let (referral_pubkey, bump_seed) = Referral::get_referral_pubkey(referrer_info.key);
+        if referral_pubkey != *referral_info.key {
+            return Err(PriceError::WrongReferralPDA.into());
+        }
+
+        let referral = Referral {
+            referrer: referrer_info.key.to_bytes(),
+            purchases: 0,
+            total_earned: 0,
+            total_claimed: 0,
+        };
+        let space = referral.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let lamports = rent.minimum_balance(space);
+        let signer_seeds: &[&[_]] = &[
+            REFERRAL_SEED.as_bytes(),
+            &referrer_info.key.to_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                referrer_info.key,
+                &referral_pubkey,
+                lamports,
+                space as u64,
+                &id(),
+            ),
+            &[
+                referrer_info.clone(),
+                referral_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+
+        referral.serialize(&mut &mut referral_info.data.borrow_mut()[..])?;
+        StoreEvent::ReferrerRegistered {
+            referrer: referrer_info.key.to_bytes(),
+            referral: referral_pubkey.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_claim_referral(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let referrer_info = next_account_info(acc_iter)?;
+        let referral_info = next_account_info(acc_iter)?;
+
+        if !referrer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !Referral::is_pubkey_ok(referrer_info.key, referral_info.key) {
+            return Err(PriceError::WrongReferralPDA.into());
+        }
+        let mut referral = Referral::try_from_slice(&referral_info.data.borrow())?;
+        let lamports = referral.claimable();
+        referral.total_claimed = referral.total_earned;
+
+        **referral_info.try_borrow_mut_lamports()? -= lamports;
+        **referrer_info.try_borrow_mut_lamports()? += lamports;
+        referral.serialize(&mut &mut referral_info.data.borrow_mut()[..])?;
+        StoreEvent::ReferralClaimed {
+            referrer: referral.referrer,
+            lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Moves the referrer's share of a purchase from the buyer into the
+    /// referral PDA and records it there.
+    fn pay_referral<'a>(
+        user_info: &AccountInfo<'a>,
+        referral_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        share: u64,
+    ) -> ProgramResult {
+        if referral_info.owner != &id() {
+            return Err(PriceError::WrongReferralPDA.into());
+        }
+        let mut referral = Referral::try_from_slice(&referral_info.data.borrow())?;
+        let referrer = Pubkey::new_from_array(referral.referrer);
+        if !Referral::is_pubkey_ok(&referrer, referral_info.key) {
+            return Err(PriceError::WrongReferralPDA.into());
+        }
+        if referrer == *user_info.key {
+            return Err(PriceError::SelfReferral.into());
+        }
+
+        invoke(
+            &system_instruction::transfer(user_info.key, referral_info.key, share),
+            &[
+                user_info.clone(),
+                referral_info.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+        referral.purchases += 1;
+        referral.total_earned = referral
+            .total_earned
+            .checked_add(share)
+            .ok_or(PriceError::Overflow)?;
+        referral.serialize(&mut &mut referral_info.data.borrow_mut()[..])?;
+        StoreEvent::ReferralPaid {
+            referrer: referral.referrer,
+            lamports: share,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_buy(
+        accounts: &[AccountInfo],
+        amount: u64,
+        receipt_id: Option<u64>,
+        source: BuySource,
+        allow_partial: bool,
+        create_associated: bool,
+        voucher: Option<Voucher>,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
         let user_info = next_account_info(acc_iter)?;
         let user_token_info = next_account_info(acc_iter)?;
-        let token_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let store_token_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
 
-        let ix = spl_token::instruction::transfer(
-            token_info.key,
-            user_token_info.key,
-            store_token_info.key,
-            user_info.key,
-            &[user_info.key],
-            10,
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let store_bump = Self::check_store(store_info)?;
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        // Listings are the seller's tokens, outside the store's vesting and limits.
+        // Refunds are paid by the store, which only holds the proceeds of its own sales.
+        let store_sale = source != BuySource::Listing;
+        let receipt = match receipt_id {
+            Some(_) if settings.refund_window == 0 || !store_sale => {
+                return Err(PriceError::RefundsDisabled.into());
+            }
+            Some(receipt_id) => Some((receipt_id, next_account_info(acc_iter)?)),
+            None => None,
+        };
+        let vesting_info = if settings.vesting.is_enabled() && store_sale {
+            Some(next_account_info(acc_iter)?)
+        } else {
+            None
+        };
+
+        // In mint-on-demand mode account 3 is the mint, which is never short
+        let requested = amount;
+        let mut amount = amount;
+        if store_token_info.data_len() == spl_token::state::Account::LEN {
+            let vault = spl_token::state::Account::unpack(&store_token_info.data.borrow())?;
+            amount = Self::fill_amount(amount, vault.amount, allow_partial)?;
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        let mut product = None;
+        let mut listing = None;
+        match source {
+            BuySource::Store => {}
+            BuySource::Product(product_id) => {
+                let product_info = next_account_info(acc_iter)?;
+                let mut loaded = Self::load_product(product_info, product_id)?;
+                if !loaded.active {
+                    return Err(PriceError::ProductInactive.into());
+                }
+                amount = Self::fill_amount(amount, loaded.stock, allow_partial)
+                    .map_err(|_| PriceError::OutOfStock)?;
+                loaded.stock -= amount;
+                product = Some((product_info, loaded));
+            }
+            BuySource::Listing => {
+                let listing_info = next_account_info(acc_iter)?;
+                let seller_info = next_account_info(acc_iter)?;
+                let mut loaded = Self::load_listing(listing_info)?;
+                if loaded.seller != seller_info.key.to_bytes()
+                    || loaded.escrow != store_token_info.key.to_bytes()
+                {
+                    return Err(PriceError::WrongListingPDA.into());
+                }
+                amount = Self::fill_amount(amount, loaded.amount, allow_partial)
+                    .map_err(|_| PriceError::OutOfStock)?;
+                loaded.amount -= amount;
+                listing = Some((listing_info, seller_info, loaded));
+            }
+        }
+        let price = match (&product, &listing) {
+            (Some((_, product)), _) => product.price,
+            (_, Some((_, _, listing))) => listing.price,
+            _ => settings.buy_price_at(now),
+        };
+        let mut lamports = amount
+            .checked_mul(price as u64)
+            .ok_or(PriceError::Overflow)?;
+        if store_sale {
+            Self::track_limits(&mut settings, amount, amount, 0)?;
+        }
+        if let Some(voucher) = voucher {
+            let instructions_info = next_account_info(acc_iter)?;
+            let nonce_info = next_account_info(acc_iter)?;
+            lamports -= Self::redeem_voucher(
+                &settings,
+                &voucher,
+                lamports,
+                user_info,
+                instructions_info,
+                nonce_info,
+                rent_info,
+                system_program_info,
+            )?;
+        }
+
+        if create_associated {
+            Self::create_associated_account(
+                user_info,
+                user_token_info,
+                acc_iter,
+                token_program_info,
+                system_program_info,
+            )?;
+        }
+        let mint = match (&product, &listing) {
+            (Some((_, product)), _) => Some(product.mint),
+            (_, Some((_, _, listing))) => Some(listing.mint),
+            _ => None,
+        };
+        let mint = Self::check_buyer_token(
+            &settings,
+            token_program_info,
+            user_token_info,
+            store_token_info,
+            mint,
+        )?;
+        if let Some((product_info, product)) = &product {
+            product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+        }
+        if let Some(vesting_info) = vesting_info {
+            Self::record_vesting(user_info, user_token_info, vesting_info, amount)?;
+        }
+
+        if settings.quote == Quote::WrappedSol {
+            Self::unwrap_quote(
+                user_info,
+                acc_iter,
+                rent_info,
+                token_program_info,
+                system_program_info,
+                lamports,
+            )?;
+        }
+
+        // Products and listings are priced apart from the store token, so only
+        // store token trades feed the price history and the accumulators
+        if product.is_none() && listing.is_none() {
+            if let Some(history_info) =
+                Self::next_price_history(&settings, HistoryMode::PriceChangesAndTrades, acc_iter)?
+            {
+                Self::record_price(history_info, price)?;
+            }
+            settings.accumulators.record(Side::Buy, price, amount, lamports, now);
+        }
+
+        let store_lamports = Self::pay_for_buy(
+            &settings,
+            user_info,
+            store_info,
+            listing.as_ref().map(|(_, seller_info, _)| *seller_info),
+            acc_iter,
+            system_program_info,
+            lamports,
         )?;
+        match &listing {
+            Some((listing_info, _, listing)) => {
+                Self::invoke_as_listing(
+                    listing,
+                    &spl_token::instruction::transfer(
+                        token_program_info.key,
+                        store_token_info.key,
+                        user_token_info.key,
+                        listing_info.key,
+                        &[],
+                        amount,
+                    )?,
+                    &[
+                        store_token_info.clone(),
+                        user_token_info.clone(),
+                        (*listing_info).clone(),
+                        token_program_info.clone(),
+                    ],
+                )?;
+                listing.serialize(&mut &mut listing_info.data.borrow_mut()[..])?;
+            }
+            None => {
+                let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+                invoke_signed(
+                    &Self::tokens_out_ix(
+                        settings.inventory,
+                        token_program_info.key,
+                        store_token_info.key,
+                        user_token_info.key,
+                        store_info.key,
+                        amount,
+                    )?,
+                    &[
+                        store_token_info.clone(),
+                        user_token_info.clone(),
+                        store_info.clone(),
+                        token_program_info.clone(),
+                    ],
+                    &[signer_seeds],
+                )?;
+            }
+        }
+
+        if let Some((receipt_id, receipt_info)) = receipt {
+            let receipt = Receipt {
+                buyer: user_info.key.to_bytes(),
+                receipt_id,
+                amount,
+                lamports: store_lamports,
+                timestamp: now,
+                mint: mint.to_bytes(),
+                product_id: product.as_ref().map(|(_, product)| product.product_id),
+            };
+            Self::create_receipt(user_info, receipt_info, rent_info, system_program_info, &receipt)?;
+        }
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        StoreEvent::Bought {
+            user: user_info.key.to_bytes(),
+            amount,
+            lamports,
+        }
+        .emit();
+        if amount < requested {
+            StoreEvent::PartiallyFilled {
+                user: user_info.key.to_bytes(),
+                requested,
+                filled: amount,
+            }
+            .emit();
+        }
+        Ok(())
+    }
+
+    /// Checks the buyer's token account against the bought mint: the product or
+    /// listing mint when given, otherwise the store's. Returns the bought mint.
+    fn check_buyer_token(
+        settings: &Settings,
+        token_program_info: &AccountInfo,
+        user_token_info: &AccountInfo,
+        store_token_info: &AccountInfo,
+        mint: Option<[u8; 32]>,
+    ) -> Result<Pubkey, ProgramError> {
+        let mint = match mint {
+            Some(mint) => mint,
+            None => {
+                let user_token = Self::unpack_token_accounts(
+                    settings,
+                    token_program_info,
+                    user_token_info,
+                    store_token_info,
+                )?;
+                return Ok(user_token.mint);
+            }
+        };
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let user_token = spl_token::state::Account::unpack(&user_token_info.data.borrow())?;
+        if user_token.mint.to_bytes() != mint {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        Ok(user_token.mint)
+    }
+
+    /// Splits a Buy payment: the seller's proceeds for a listing, then the
+    /// referral share when a referral account follows, the rest to the store.
+    /// Returns the lamports the store received.
+    fn pay_for_buy<'a, 'b>(
+        settings: &Settings,
+        user_info: &'a AccountInfo<'b>,
+        store_info: &'a AccountInfo<'b>,
+        seller_info: Option<&'a AccountInfo<'b>>,
+        acc_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        system_program_info: &'a AccountInfo<'b>,
+        lamports: u64,
+    ) -> Result<u64, ProgramError> {
+        let mut store_lamports = lamports;
+        if let Some(seller_info) = seller_info {
+            let proceeds = lamports - settings.commission(lamports);
+            invoke(
+                &system_instruction::transfer(user_info.key, seller_info.key, proceeds),
+                &[
+                    user_info.clone(),
+                    seller_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+            store_lamports -= proceeds;
+        }
+        if let Ok(referral_info) = next_account_info(acc_iter) {
+            let share = settings.referral_share(store_lamports);
+            Self::pay_referral(user_info, referral_info, system_program_info, share)?;
+            store_lamports -= share;
+        }
+
         invoke(
-            &ix,
+            &system_instruction::transfer(user_info.key, store_info.key, store_lamports),
             &[
-                user_token_info.clone(),
-                store_token_info.clone(),
                 user_info.clone(),
-                token_info.clone(),
+                store_info.clone(),
+                system_program_info.clone(),
             ],
         )?;
-        // let ixs = system_instruction::transfer(
-        //     store_info.key,
-        //     user_info.key,
-        //     10,
-        // );
-        //
-        // invoke(
-        //     &ixs,
-        //     &[store_info.clone(), user_info.clone()]
-        // );
-        Ok(())
+        Ok(store_lamports)
     }
-}
 
+    fn load_listing(listing_info: &AccountInfo) -> Result<Listing, ProgramError> {
+        if listing_info.owner != &id() {
+            return Err(PriceError::WrongListingPDA.into());
+        }
+        let listing = Listing::try_from_slice(&listing_info.data.borrow())?;
+        let seller = Pubkey::new_from_array(listing.seller);
+        if !Listing::is_pubkey_ok(&seller, listing.listing_id, listing_info.key) {
+            return Err(PriceError::WrongListingPDA.into());
+        }
+        Ok(listing)
+    }
 
+    fn invoke_as_listing(
+        listing: &Listing,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+    ) -> ProgramResult {
+        let (_, bump_seed) =
+            Listing::get_listing_pubkey(&Pubkey::new_from_array(listing.seller), listing.listing_id);
+        let listing_id_bytes = listing.listing_id.to_le_bytes();
+        let signer_seeds: &[&[_]] = &[
+            LISTING_SEED.as_bytes(),
+            &listing.seller,
+            &listing_id_bytes,
+            &[bump_seed],
+        ];
+        invoke_signed(instruction, account_infos, &[signer_seeds])
+    }
+
+    fn process_list_tokens(
+        accounts: &[AccountInfo],
+        listing_id: u64,
+        price: u32,
+        amount: u64,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let seller_info = next_account_info(acc_iter)?;
+        let seller_token_info = next_account_info(acc_iter)?;
+        let listing_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !seller_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if price == 0 || amount == 0 {
+            return Err(PriceError::InvalidListing.into());
+        }
+        let (listing_pubkey, bump_seed) = Listing::get_listing_pubkey(seller_info.key, listing_id);
+        if listing_pubkey != *listing_info.key {
+            return Err(PriceError::WrongListingPDA.into());
+        }
+        let seller_token = spl_token::state::Account::unpack(&seller_token_info.data.borrow())?;
+        let escrow = spl_token::state::Account::unpack(&escrow_info.data.borrow())?;
+        if escrow.owner != listing_pubkey || escrow.close_authority.is_some() {
+            return Err(TokenError::NoOwner.into());
+        }
+        if escrow.mint != seller_token.mint {
+            return Err(TokenError::TokenMismatch.into());
+        }
+
+        let listing = Listing {
+            seller: seller_info.key.to_bytes(),
+            listing_id,
+            mint: seller_token.mint.to_bytes(),
+            price,
+            amount,
+            escrow: escrow_info.key.to_bytes(),
+        };
+        let space = listing.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let signer_seeds: &[&[_]] = &[
+            LISTING_SEED.as_bytes(),
+            &seller_info.key.to_bytes(),
+            &listing_id.to_le_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                seller_info.key,
+                &listing_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
+            &[
+                seller_info.clone(),
+                listing_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program_info.key,
+                seller_token_info.key,
+                escrow_info.key,
+                seller_info.key,
+                &[],
+                amount,
+            )?,
+            &[
+                seller_token_info.clone(),
+                escrow_info.clone(),
+                seller_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        listing.serialize(&mut &mut listing_info.data.borrow_mut()[..])?;
+        StoreEvent::Listed {
+            listing: listing_pubkey.to_bytes(),
+            seller: listing.seller,
+            price,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_delist(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let seller_info = next_account_info(acc_iter)?;
+        let listing_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let seller_token_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !seller_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let listing = Self::load_listing(listing_info)?;
+        if listing.seller != seller_info.key.to_bytes()
+            || listing.escrow != escrow_info.key.to_bytes()
+        {
+            return Err(PriceError::WrongListingPDA.into());
+        }
+
+        if listing.amount > 0 {
+            Self::invoke_as_listing(
+                &listing,
+                &spl_token::instruction::transfer(
+                    token_program_info.key,
+                    escrow_info.key,
+                    seller_token_info.key,
+                    listing_info.key,
+                    &[],
+                    listing.amount,
+                )?,
+                &[
+                    escrow_info.clone(),
+                    seller_token_info.clone(),
+                    listing_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+        Self::invoke_as_listing(
+            &listing,
+            &spl_token::instruction::close_account(
+                token_program_info.key,
+                escrow_info.key,
+                seller_info.key,
+                listing_info.key,
+                &[],
+            )?,
+            &[
+                escrow_info.clone(),
+                seller_info.clone(),
+                listing_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        Self::move_lamports(listing_info, seller_info, listing_info.lamports())?;
+        listing_info.data.borrow_mut().fill(0);
+        StoreEvent::Delisted {
+            listing: listing_info.key.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_set_commission_bps(accounts: &[AccountInfo], commission_bps: u16) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if commission_bps > MAX_BPS {
+            return Err(PriceError::InvalidBps.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.commission_bps = commission_bps;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_set_subscription_terms(
+        accounts: &[AccountInfo],
+        terms: SubscriptionTerms,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if terms.period < 0 {
+            return Err(PriceError::InvalidInstruction.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.subscription = terms;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn load_subscription(subscription_info: &AccountInfo) -> Result<Subscription, ProgramError> {
+        if subscription_info.owner != &id() {
+            return Err(PriceError::WrongSubscriptionPDA.into());
+        }
+        let subscription = Subscription::try_from_slice(&subscription_info.data.borrow())?;
+        let subscriber = Pubkey::new_from_array(subscription.subscriber);
+        if !Subscription::is_pubkey_ok(&subscriber, subscription_info.key) {
+            return Err(PriceError::WrongSubscriptionPDA.into());
+        }
+        Ok(subscription)
+    }
+
+    fn process_subscribe(
+        accounts: &[AccountInfo],
+        funding: SubscriptionFunding,
+        deposit: u64,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let subscriber_info = next_account_info(acc_iter)?;
+        let subscription_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let deliver_to_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !subscriber_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let settings = Self::load_settings(settings_info)?;
+        if settings.subscription.period == 0 {
+            return Err(PriceError::SubscriptionsClosed.into());
+        }
+        let (subscription_pubkey, bump_seed) =
+            Subscription::get_subscription_pubkey(subscriber_info.key);
+        if subscription_pubkey != *subscription_info.key {
+            return Err(PriceError::WrongSubscriptionPDA.into());
+        }
+        let payment_account = match funding {
+            SubscriptionFunding::Delegate => {
+                let payment_info = next_account_info(acc_iter)?;
+                let payment = spl_token::state::Account::unpack(&payment_info.data.borrow())?;
+                if payment.owner != *subscriber_info.key {
+                    return Err(TokenError::NoOwner.into());
+                }
+                if payment.mint.to_bytes() != settings.subscription.payment_mint {
+                    return Err(TokenError::TokenMismatch.into());
+                }
+                payment_info.key.to_bytes()
+            }
+            SubscriptionFunding::Prefunded => [0; 32],
+        };
+
+        let subscription = Subscription {
+            subscriber: subscriber_info.key.to_bytes(),
+            funding,
+            payment_account,
+            deliver_to: deliver_to_info.key.to_bytes(),
+            next_charge: Clock::get()?.unix_timestamp,
+            active_until: 0,
+        };
+        let space = subscription.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let lamports = rent
+            .minimum_balance(space)
+            .checked_add(deposit)
+            .ok_or(PriceError::Overflow)?;
+        let signer_seeds: &[&[_]] = &[
+            SUBSCRIPTION_SEED.as_bytes(),
+            &subscriber_info.key.to_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                subscriber_info.key,
+                &subscription_pubkey,
+                lamports,
+                space as u64,
+                &id(),
+            ),
+            &[
+                subscriber_info.clone(),
+                subscription_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        subscription.serialize(&mut &mut subscription_info.data.borrow_mut()[..])?;
+        StoreEvent::Subscribed {
+            subscriber: subscriber_info.key.to_bytes(),
+            subscription: subscription_pubkey.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_charge_subscription(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let subscription_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let store_bump = Self::check_store(store_info)?;
+        let settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        let terms = settings.subscription;
+        if terms.period == 0 {
+            return Err(PriceError::SubscriptionsClosed.into());
+        }
+        let mut subscription = Self::load_subscription(subscription_info)?;
+        let now = Clock::get()?.unix_timestamp;
+        if now < subscription.next_charge {
+            return Err(PriceError::ChargeNotDue.into());
+        }
+
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        match subscription.funding {
+            SubscriptionFunding::Delegate => {
+                let payment_info = next_account_info(acc_iter)?;
+                let store_payment_info = next_account_info(acc_iter)?;
+                if payment_info.key.to_bytes() != subscription.payment_account {
+                    return Err(TokenError::TokenMismatch.into());
+                }
+                let payment = spl_token::state::Account::unpack(&payment_info.data.borrow())?;
+                let store_payment =
+                    spl_token::state::Account::unpack(&store_payment_info.data.borrow())?;
+                if store_payment.owner != *store_info.key {
+                    return Err(TokenError::NoOwner.into());
+                }
+                if payment.mint.to_bytes() != terms.payment_mint
+                    || store_payment.mint != payment.mint
+                {
+                    return Err(TokenError::TokenMismatch.into());
+                }
+                if payment.delegate != COption::Some(*subscription_info.key) {
+                    return Err(TokenError::NotDelegate.into());
+                }
+                if payment.delegated_amount < terms.price || payment.amount < terms.price {
+                    return Err(TokenError::InsufficientFunds.into());
+                }
+                let (_, subscription_bump) = Subscription::get_subscription_pubkey(
+                    &Pubkey::new_from_array(subscription.subscriber),
+                );
+                let subscription_seeds: &[&[_]] = &[
+                    SUBSCRIPTION_SEED.as_bytes(),
+                    &subscription.subscriber,
+                    &[subscription_bump],
+                ];
+                invoke_signed(
+                    &spl_token::instruction::transfer(
+                        token_program_info.key,
+                        payment_info.key,
+                        store_payment_info.key,
+                        subscription_info.key,
+                        &[],
+                        terms.price,
+                    )?,
+                    &[
+                        payment_info.clone(),
+                        store_payment_info.clone(),
+                        subscription_info.clone(),
+                        token_program_info.clone(),
+                    ],
+                    &[subscription_seeds],
+                )?;
+            }
+            SubscriptionFunding::Prefunded => {
+                // The deposit is charged down to the rent-exempt minimum
+                let reserve = Rent::get()?.minimum_balance(subscription_info.data_len());
+                let available = subscription_info.lamports().saturating_sub(reserve);
+                if available < terms.price {
+                    return Err(TokenError::InsufficientFunds.into());
+                }
+                Self::move_lamports(subscription_info, store_info, terms.price)?;
+            }
+        }
+
+        if terms.delivery_amount > 0 {
+            let store_token_info = next_account_info(acc_iter)?;
+            let deliver_to_info = next_account_info(acc_iter)?;
+            if deliver_to_info.key.to_bytes() != subscription.deliver_to {
+                return Err(TokenError::TokenMismatch.into());
+            }
+            Self::unpack_token_accounts(
+                &settings,
+                token_program_info,
+                deliver_to_info,
+                store_token_info,
+            )?;
+            let ix = Self::tokens_out_ix(
+                settings.inventory,
+                token_program_info.key,
+                store_token_info.key,
+                deliver_to_info.key,
+                store_info.key,
+                terms.delivery_amount,
+            )?;
+            invoke_signed(
+                &ix,
+                &[
+                    store_token_info.clone(),
+                    deliver_to_info.clone(),
+                    store_info.clone(),
+                    token_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+
+        let active_until = subscription
+            .renew(terms.period, now)
+            .ok_or(PriceError::Overflow)?;
+        subscription.serialize(&mut &mut subscription_info.data.borrow_mut()[..])?;
+        StoreEvent::SubscriptionCharged {
+            subscriber: subscription.subscriber,
+            amount: terms.price,
+            active_until,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_cancel_subscription(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let subscriber_info = next_account_info(acc_iter)?;
+        let subscription_info = next_account_info(acc_iter)?;
+
+        if !subscriber_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let subscription = Self::load_subscription(subscription_info)?;
+        if subscription.subscriber != subscriber_info.key.to_bytes() {
+            return Err(PriceError::WrongSubscriptionPDA.into());
+        }
+        Self::move_lamports(subscription_info, subscriber_info, subscription_info.lamports())?;
+        subscription_info.data.borrow_mut().fill(0);
+        StoreEvent::SubscriptionCancelled {
+            subscriber: subscription.subscriber,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn load_invoice(invoice_info: &AccountInfo) -> Result<Invoice, ProgramError> {
+        if invoice_info.owner != &id() {
+            return Err(PriceError::WrongInvoicePDA.into());
+        }
+        let invoice = Invoice::try_from_slice(&invoice_info.data.borrow())?;
+        let merchant = Pubkey::new_from_array(invoice.merchant);
+        if !Invoice::is_pubkey_ok(&merchant, invoice.invoice_id, invoice_info.key) {
+            return Err(PriceError::WrongInvoicePDA.into());
+        }
+        Ok(invoice)
+    }
+
+    fn process_create_invoice(
+        accounts: &[AccountInfo],
+        invoice_id: u64,
+        amount: u64,
+        mint: [u8; 32],
+        memo: String,
+        expiry: i64,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let merchant_info = next_account_info(acc_iter)?;
+        let invoice_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !merchant_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount == 0
+            || memo.len() > MAX_MEMO_LEN
+            || (expiry != 0 && expiry <= Clock::get()?.unix_timestamp)
+        {
+            return Err(PriceError::InvalidInvoice.into());
+        }
+        let (invoice_pubkey, bump_seed) =
+            Invoice::get_invoice_pubkey(merchant_info.key, invoice_id);
+        if invoice_pubkey != *invoice_info.key {
+            return Err(PriceError::WrongInvoicePDA.into());
+        }
+
+        let invoice = Invoice {
+            merchant: merchant_info.key.to_bytes(),
+            invoice_id,
+            amount,
+            mint,
+            memo,
+            expiry,
+            status: InvoiceStatus::Open,
+            payer: [0; 32],
+            paid_at: 0,
+        };
+        let space = invoice.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let signer_seeds: &[&[_]] = &[
+            INVOICE_SEED.as_bytes(),
+            &merchant_info.key.to_bytes(),
+            &invoice_id.to_le_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                merchant_info.key,
+                &invoice_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
+            &[
+                merchant_info.clone(),
+                invoice_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        invoice.serialize(&mut &mut invoice_info.data.borrow_mut()[..])?;
+        StoreEvent::InvoiceCreated {
+            invoice: invoice_pubkey.to_bytes(),
+            merchant: invoice.merchant,
+            invoice_id,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_pay_invoice(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let payer_info = next_account_info(acc_iter)?;
+        let invoice_info = next_account_info(acc_iter)?;
+        let merchant_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Self::check_store(store_info)?;
+        let settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        let mut invoice = Self::load_invoice(invoice_info)?;
+        let now = Clock::get()?.unix_timestamp;
+        if invoice.status_at(now) != InvoiceStatus::Open {
+            return Err(PriceError::InvoiceNotPayable.into());
+        }
+        let commission = settings.commission(invoice.amount);
+        let proceeds = invoice.amount - commission;
+
+        if invoice.is_lamports() {
+            if merchant_info.key.to_bytes() != invoice.merchant {
+                return Err(PriceError::WrongInvoicePDA.into());
+            }
+            if settings.quote == Quote::WrappedSol {
+                Self::unwrap_quote(
+                    payer_info,
+                    acc_iter,
+                    rent_info,
+                    token_program_info,
+                    system_program_info,
+                    invoice.amount,
+                )?;
+            }
+            invoke(
+                &system_instruction::transfer(payer_info.key, merchant_info.key, proceeds),
+                &[
+                    payer_info.clone(),
+                    merchant_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+            if commission > 0 {
+                invoke(
+                    &system_instruction::transfer(payer_info.key, store_info.key, commission),
+                    &[
+                        payer_info.clone(),
+                        store_info.clone(),
+                        system_program_info.clone(),
+                    ],
+                )?;
+            }
+        } else {
+            let payer_token_info = next_account_info(acc_iter)?;
+            let store_token_info = next_account_info(acc_iter)?;
+            let merchant_token =
+                spl_token::state::Account::unpack(&merchant_info.data.borrow())?;
+            let store_token = spl_token::state::Account::unpack(&store_token_info.data.borrow())?;
+            if merchant_token.owner.to_bytes() != invoice.merchant
+                || store_token.owner != *store_info.key
+            {
+                return Err(TokenError::NoOwner.into());
+            }
+            if merchant_token.mint.to_bytes() != invoice.mint
+                || store_token.mint.to_bytes() != invoice.mint
+            {
+                return Err(TokenError::TokenMismatch.into());
+            }
+            for (destination_info, amount) in
+                [(merchant_info, proceeds), (store_token_info, commission)]
+            {
+                if amount == 0 {
+                    continue;
+                }
+                invoke(
+                    &spl_token::instruction::transfer(
+                        token_program_info.key,
+                        payer_token_info.key,
+                        destination_info.key,
+                        payer_info.key,
+                        &[],
+                        amount,
+                    )?,
+                    &[
+                        payer_token_info.clone(),
+                        destination_info.clone(),
+                        payer_info.clone(),
+                        token_program_info.clone(),
+                    ],
+                )?;
+            }
+        }
+
+        invoice.status = InvoiceStatus::Paid;
+        invoice.payer = payer_info.key.to_bytes();
+        invoice.paid_at = now;
+        invoice.serialize(&mut &mut invoice_info.data.borrow_mut()[..])?;
+        StoreEvent::InvoicePaid {
+            invoice: invoice_info.key.to_bytes(),
+            payer: invoice.payer,
+            amount: invoice.amount,
+            commission,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_expire_invoice(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let invoice_info = next_account_info(acc_iter)?;
+
+        let mut invoice = Self::load_invoice(invoice_info)?;
+        if invoice.status != InvoiceStatus::Open
+            || invoice.status_at(Clock::get()?.unix_timestamp) != InvoiceStatus::Expired
+        {
+            return Err(PriceError::InvoiceNotExpired.into());
+        }
+        invoice.status = InvoiceStatus::Expired;
+        invoice.serialize(&mut &mut invoice_info.data.borrow_mut()[..])?;
+        StoreEvent::InvoiceExpired {
+            invoice: invoice_info.key.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_set_escrow_timeout(accounts: &[AccountInfo], escrow_timeout: i64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if escrow_timeout < 0 {
+            return Err(PriceError::InvalidInstruction.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.escrow_timeout = escrow_timeout;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn load_escrow(escrow_info: &AccountInfo) -> Result<Escrow, ProgramError> {
+        if escrow_info.owner != &id() {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+        let escrow = Escrow::try_from_slice(&escrow_info.data.borrow())?;
+        let buyer = Pubkey::new_from_array(escrow.buyer);
+        if !Escrow::is_pubkey_ok(&buyer, escrow.escrow_id, escrow_info.key) {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+        Ok(escrow)
+    }
+
+    fn process_open_escrow(accounts: &[AccountInfo], escrow_id: u64, lamports: u64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let buyer_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let seller_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !buyer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        if lamports == 0 || settings.escrow_timeout == 0 {
+            return Err(PriceError::InvalidEscrow.into());
+        }
+        let (escrow_pubkey, bump_seed) = Escrow::get_escrow_pubkey(buyer_info.key, escrow_id);
+        if escrow_pubkey != *escrow_info.key {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+
+        let escrow = Escrow {
+            buyer: buyer_info.key.to_bytes(),
+            seller: seller_info.key.to_bytes(),
+            escrow_id,
+            lamports,
+            status: EscrowStatus::Funded,
+            release_after: 0,
+        };
+        let space = escrow.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let signer_seeds: &[&[_]] = &[
+            ESCROW_SEED.as_bytes(),
+            &buyer_info.key.to_bytes(),
+            &escrow_id.to_le_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                buyer_info.key,
+                &escrow_pubkey,
+                rent.minimum_balance(space)
+                    .checked_add(lamports)
+                    .ok_or(PriceError::Overflow)?,
+                space as u64,
+                &id(),
+            ),
+            &[
+                buyer_info.clone(),
+                escrow_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        escrow.serialize(&mut &mut escrow_info.data.borrow_mut()[..])?;
+        StoreEvent::EscrowOpened {
+            escrow: escrow_pubkey.to_bytes(),
+            buyer: escrow.buyer,
+            seller: escrow.seller,
+            lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_confirm_delivery(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let seller_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if !seller_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let settings = Self::load_settings(settings_info)?;
+        let mut escrow = Self::load_escrow(escrow_info)?;
+        if escrow.seller != seller_info.key.to_bytes() {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+        if escrow.status != EscrowStatus::Funded {
+            return Err(PriceError::WrongEscrowStatus.into());
+        }
+        escrow.status = EscrowStatus::Delivered;
+        escrow.release_after = Clock::get()?
+            .unix_timestamp
+            .checked_add(settings.escrow_timeout)
+            .ok_or(PriceError::Overflow)?;
+        escrow.serialize(&mut &mut escrow_info.data.borrow_mut()[..])?;
+        StoreEvent::DeliveryConfirmed {
+            escrow: escrow_info.key.to_bytes(),
+            release_after: escrow.release_after,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Pays out an escrow: `refund` lamports back to the buyer and the rest to
+    /// the seller, less the store commission. The escrow account is closed to
+    /// the buyer. Returns the seller's lamports and the commission.
+    fn settle_escrow(
+        settings: &Settings,
+        escrow: &Escrow,
+        escrow_info: &AccountInfo,
+        buyer_info: &AccountInfo,
+        seller_info: &AccountInfo,
+        store_info: &AccountInfo,
+        refund: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        if buyer_info.key.to_bytes() != escrow.buyer || seller_info.key.to_bytes() != escrow.seller
+        {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+        Self::check_store(store_info)?;
+        let seller_share = escrow.lamports - refund;
+        let commission = settings.commission(seller_share);
+        Self::move_lamports(escrow_info, seller_info, seller_share - commission)?;
+        Self::move_lamports(escrow_info, store_info, commission)?;
+        Self::move_lamports(escrow_info, buyer_info, escrow_info.lamports())?;
+        escrow_info.data.borrow_mut().fill(0);
+        Ok((seller_share - commission, commission))
+    }
+
+    fn process_release_escrow(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let caller_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let buyer_info = next_account_info(acc_iter)?;
+        let seller_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if !caller_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let settings = Self::load_settings(settings_info)?;
+        let escrow = Self::load_escrow(escrow_info)?;
+        if !escrow.can_release(caller_info.key, Clock::get()?.unix_timestamp) {
+            return Err(PriceError::EscrowLocked.into());
+        }
+        let (seller_lamports, commission) = Self::settle_escrow(
+            &settings,
+            &escrow,
+            escrow_info,
+            buyer_info,
+            seller_info,
+            store_info,
+            0,
+        )?;
+        StoreEvent::EscrowReleased {
+            escrow: escrow_info.key.to_bytes(),
+            seller_lamports,
+            commission,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_open_dispute(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let party_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+
+        if !party_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut escrow = Self::load_escrow(escrow_info)?;
+        let party = party_info.key.to_bytes();
+        if party != escrow.buyer && party != escrow.seller {
+            return Err(PriceError::WrongEscrowPDA.into());
+        }
+        if escrow.status == EscrowStatus::Disputed {
+            return Err(PriceError::WrongEscrowStatus.into());
+        }
+        if !escrow.can_dispute(Clock::get()?.unix_timestamp) {
+            return Err(PriceError::DisputeWindowClosed.into());
+        }
+        escrow.status = EscrowStatus::Disputed;
+        escrow.serialize(&mut &mut escrow_info.data.borrow_mut()[..])?;
+        StoreEvent::DisputeOpened {
+            escrow: escrow_info.key.to_bytes(),
+            by: party,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_resolve_dispute(accounts: &[AccountInfo], buyer_bps: u16) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let buyer_info = next_account_info(acc_iter)?;
+        let seller_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+
+        if buyer_bps > MAX_BPS {
+            return Err(PriceError::InvalidBps.into());
+        }
+        let settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        let escrow = Self::load_escrow(escrow_info)?;
+        if escrow.status != EscrowStatus::Disputed {
+            return Err(PriceError::WrongEscrowStatus.into());
+        }
+        let (buyer_lamports, _) = escrow.split(buyer_bps);
+        let (seller_lamports, commission) = Self::settle_escrow(
+            &settings,
+            &escrow,
+            escrow_info,
+            buyer_info,
+            seller_info,
+            store_info,
+            buyer_lamports,
+        )?;
+        StoreEvent::DisputeResolved {
+            escrow: escrow_info.key.to_bytes(),
+            buyer_lamports,
+            seller_lamports,
+            commission,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_set_revenue_split(
+        accounts: &[AccountInfo],
+        shares: Vec<RevenueShare>,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let revenue_split = RevenueSplit::new(&shares)?;
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.revenue_split = revenue_split;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_distribute(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let treasurer_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        let settings = Self::load_settings(settings_info)?;
+        let split = settings.revenue_split;
+        if split.len == 0 {
+            return Err(PriceError::InvalidRevenueSplit.into());
+        }
+        // The multisig signers follow the beneficiaries
+        let remaining = acc_iter.as_slice();
+        if remaining.len() < split.len as usize {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (beneficiary_infos, signer_infos) = remaining.split_at(split.len as usize);
+        Self::validate_authority(&settings.treasurer, treasurer_info, signer_infos)?;
+        let store_bump = Self::check_store(store_info)?;
+        let lamports = store_info
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(store_info.data_len()));
+        let payouts = split.payouts(lamports);
+
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        for ((share, payout), beneficiary_info) in
+            split.active().iter().zip(&payouts).zip(beneficiary_infos)
+        {
+            if beneficiary_info.key.to_bytes() != share.beneficiary {
+                return Err(PriceError::InvalidRevenueSplit.into());
+            }
+            if *payout == 0 {
+                continue;
+            }
+            invoke_signed(
+                &system_instruction::transfer(store_info.key, beneficiary_info.key, *payout),
+                &[
+                    store_info.clone(),
+                    beneficiary_info.clone(),
+                    system_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+        StoreEvent::RevenueDistributed { lamports, payouts }.emit();
+        Ok(())
+    }
+
+    fn load_product(product_info: &AccountInfo, product_id: u64) -> Result<Product, ProgramError> {
+        if product_info.owner != &id() || !Product::is_pubkey_ok(product_id, product_info.key) {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+        Ok(Product::try_from_slice(&product_info.data.borrow())?)
+    }
+
+    fn process_add_product(
+        accounts: &[AccountInfo],
+        product_id: u64,
+        mint: [u8; 32],
+        price: u32,
+        stock: u64,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let payer_info = next_account_info(acc_iter)?;
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let product_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let settings = Self::load_settings(settings_info)?;
+        // A removed product could otherwise come back at any price
+        if settings.timelock_delay != 0 {
+            return Err(PriceError::TimelockActive.into());
+        }
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        let (product_pubkey, bump_seed) = Product::get_product_pubkey(product_id);
+        if product_pubkey != *product_info.key {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+
+        let product = Product {
+            product_id,
+            mint,
+            price,
+            stock,
+            active: true,
+        };
+        let space = product.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let signer_seeds: &[&[_]] = &[
+            PRODUCT_SEED.as_bytes(),
+            &product_id.to_le_bytes(),
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_info.key,
+                &product_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
+            &[
+                payer_info.clone(),
+                product_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+        StoreEvent::ProductUpdated { product }.emit();
+        Ok(())
+    }
+
+    fn process_update_product(
+        accounts: &[AccountInfo],
+        price: u32,
+        stock: u64,
+        active: bool,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let product_info = next_account_info(acc_iter)?;
+
+        let settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        if product_info.owner != &id() {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+        let mut product = Product::try_from_slice(&product_info.data.borrow())?;
+        if !Product::is_pubkey_ok(product.product_id, product_info.key) {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+        if settings.timelock_delay != 0 && price != product.price {
+            return Err(PriceError::TimelockActive.into());
+        }
+        product.price = price;
+        product.stock = stock;
+        product.active = active;
+        product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+        StoreEvent::ProductUpdated { product }.emit();
+        Ok(())
+    }
+
+    fn process_remove_product(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let product_info = next_account_info(acc_iter)?;
+        let recipient_info = next_account_info(acc_iter)?;
+
+        let settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        if product_info.owner != &id() {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+        let product = Product::try_from_slice(&product_info.data.borrow())?;
+        if !Product::is_pubkey_ok(product.product_id, product_info.key) {
+            return Err(PriceError::WrongProductPDA.into());
+        }
+        Self::move_lamports(product_info, recipient_info, product_info.lamports())?;
+        product_info.data.borrow_mut().fill(0);
+        StoreEvent::ProductRemoved {
+            product_id: product.product_id,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_checkout(accounts: &[AccountInfo], items: Vec<CartItem>) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let user_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if items.is_empty() {
+            return Err(PriceError::InvalidInstruction.into());
+        }
+        let store_bump = Self::check_store(store_info)?;
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        // The cart delivers straight to the user and pays in lamports, without
+        // the vesting escrow, receipts or wSOL settlement Buy goes through
+        if settings.vesting.is_enabled()
+            || settings.refund_window != 0
+            || settings.quote == Quote::WrappedSol
+        {
+            return Err(PriceError::CheckoutUnavailable.into());
+        }
+
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        let mut total: u64 = 0;
+        for item in items {
+            let product_info = next_account_info(acc_iter)?;
+            let store_token_info = next_account_info(acc_iter)?;
+            let user_token_info = next_account_info(acc_iter)?;
+
+            let mut product = Self::load_product(product_info, item.product_id)?;
+            if !product.active {
+                return Err(PriceError::ProductInactive.into());
+            }
+            product.stock = product
+                .stock
+                .checked_sub(item.quantity)
+                .ok_or(PriceError::OutOfStock)?;
+            let user_token = Self::unpack_store_owned_accounts(
+                token_program_info,
+                user_token_info,
+                store_token_info,
+            )?;
+            if user_token.mint.to_bytes() != product.mint {
+                return Err(TokenError::TokenMismatch.into());
+            }
+            let lamports = product.lamports_for(item.quantity).ok_or(PriceError::Overflow)?;
+            total = total.checked_add(lamports).ok_or(PriceError::Overflow)?;
+            Self::track_limits(&mut settings, item.quantity, item.quantity, 0)?;
+            product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+
+            invoke_signed(
+                &Self::tokens_out_ix(
+                    settings.inventory,
+                    token_program_info.key,
+                    store_token_info.key,
+                    user_token_info.key,
+                    store_info.key,
+                    item.quantity,
+                )?,
+                &[
+                    store_token_info.clone(),
+                    user_token_info.clone(),
+                    store_info.clone(),
+                    token_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+            StoreEvent::Bought {
+                user: user_info.key.to_bytes(),
+                amount: item.quantity,
+                lamports,
+            }
+            .emit();
+        }
+
+        invoke(
+            &system_instruction::transfer(user_info.key, store_info.key, total),
+            &[
+                user_info.clone(),
+                store_info.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        Ok(())
+    }
+
+    /// Creates the buyer's associated token account through the associated
+    /// token account program, with the buyer paying rent. Does nothing when it
+    /// already exists, so clients can always set `create_associated`.
+    fn create_associated_account<'a, 'b>(
+        user_info: &'a AccountInfo<'b>,
+        user_token_info: &'a AccountInfo<'b>,
+        acc_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        token_program_info: &AccountInfo<'b>,
+        system_program_info: &AccountInfo<'b>,
+    ) -> ProgramResult {
+        let mint_info = next_account_info(acc_iter)?;
+        let associated_program_info = next_account_info(acc_iter)?;
+
+        if *associated_program_info.key != spl_associated_token_account::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let associated_pubkey = spl_associated_token_account::get_associated_token_address(
+            user_info.key,
+            mint_info.key,
+        );
+        if associated_pubkey != *user_token_info.key {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        invoke(
+            &spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                user_info.key,
+                user_info.key,
+                mint_info.key,
+                token_program_info.key,
+            ),
+            &[
+                user_info.clone(),
+                user_token_info.clone(),
+                user_info.clone(),
+                mint_info.clone(),
+                system_program_info.clone(),
+                token_program_info.clone(),
+                associated_program_info.clone(),
+            ],
+        )
+    }
+
+    /// Checks the admin signature over `voucher` in the preceding Ed25519 program
+    /// instruction and burns its nonce by creating the nonce PDA. Returns the
+    /// discount on `lamports`.
+    #[allow(clippy::too_many_arguments)]
+    fn redeem_voucher<'a>(
+        settings: &Settings,
+        voucher: &Voucher,
+        lamports: u64,
+        user_info: &AccountInfo<'a>,
+        instructions_info: &AccountInfo<'a>,
+        nonce_info: &AccountInfo<'a>,
+        rent_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+    ) -> Result<u64, ProgramError> {
+        if voucher.buyer != user_info.key.to_bytes() || !voucher.is_valid() {
+            return Err(PriceError::InvalidVoucher.into());
+        }
+        if Clock::get()?.unix_timestamp > voucher.expiry {
+            return Err(PriceError::VoucherExpired.into());
+        }
+
+        if *instructions_info.key != instructions::id() {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let current_index = instructions::load_current_index_checked(instructions_info)?;
+        if current_index == 0 {
+            return Err(PriceError::InvalidVoucher.into());
+        }
+        let ed25519_ix =
+            instructions::load_instruction_at_checked(current_index as usize - 1, instructions_info)?;
+        if !voucher.is_signed_in(&ed25519_ix, &settings.admin) {
+            return Err(PriceError::InvalidVoucher.into());
+        }
+
+        let (nonce_pubkey, bump_seed) =
+            VoucherNonce::get_nonce_pubkey(user_info.key, voucher.nonce);
+        if nonce_pubkey != *nonce_info.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        // Lamports alone don't mark the nonce, anyone can send those
+        if nonce_info.owner == &id() {
+            return Err(PriceError::VoucherRedeemed.into());
+        }
+        let signer_seeds: &[&[_]] = &[
+            VOUCHER_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &voucher.nonce.to_le_bytes(),
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            user_info,
+            nonce_info,
+            0,
+            &id(),
+            &Rent::from_account_info(rent_info)?,
+            system_program_info,
+            signer_seeds,
+        )?;
+        let discount = voucher.discount(lamports);
+        StoreEvent::VoucherRedeemed {
+            buyer: voucher.buyer,
+            nonce: voucher.nonce,
+            discount,
+        }
+        .emit();
+        Ok(discount)
+    }
+
+    /// Creates a PDA account owned by `owner`. Unlike `create_account` this also
+    /// works when the address already holds lamports: the rent shortfall is
+    /// topped up by `payer_info`, then the PDA allocates and assigns itself.
+    fn create_pda_account<'a>(
+        payer_info: &AccountInfo<'a>,
+        pda_info: &AccountInfo<'a>,
+        space: usize,
+        owner: &Pubkey,
+        rent: &Rent,
+        system_program_info: &AccountInfo<'a>,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let shortfall = rent.minimum_balance(space).saturating_sub(pda_info.lamports());
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(payer_info.key, pda_info.key, shortfall),
+                &[
+                    payer_info.clone(),
+                    pda_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(pda_info.key, space as u64),
+            &[pda_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(pda_info.key, owner),
+            &[pda_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    fn create_receipt<'a>(
+        user_info: &AccountInfo<'a>,
+        receipt_info: &AccountInfo<'a>,
+        rent_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        receipt: &Receipt,
+    ) -> ProgramResult {
+        let (receipt_pubkey, bump_seed) =
+            Receipt::get_receipt_pubkey(user_info.key, receipt.receipt_id);
+        if receipt_pubkey != *receipt_info.key {
+            return Err(PriceError::WrongReceiptPDA.into());
+        }
+        let space = receipt.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let receipt_id_bytes = receipt.receipt_id.to_le_bytes();
+        let signer_seeds: &[&[_]] = &[
+            RECEIPT_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &receipt_id_bytes,
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                user_info.key,
+                &receipt_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
+            &[
+                user_info.clone(),
+                receipt_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        receipt.serialize(&mut &mut receipt_info.data.borrow_mut()[..])?;
+        Ok(())
+    }
+
+    fn process_set_refund_window(accounts: &[AccountInfo], refund_window: i64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if refund_window < 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.refund_window = refund_window;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_refund(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let user_info = next_account_info(acc_iter)?;
+        let user_token_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let store_token_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let receipt_info = next_account_info(acc_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let store_bump = Self::check_store(store_info)?;
+        let settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        if receipt_info.owner != &id() {
+            return Err(PriceError::WrongReceiptPDA.into());
+        }
+        let receipt = Receipt::try_from_slice(&receipt_info.data.borrow())?;
+        if receipt.buyer != user_info.key.to_bytes()
+            || !Receipt::is_pubkey_ok(user_info.key, receipt.receipt_id, receipt_info.key)
+        {
+            return Err(PriceError::WrongReceiptPDA.into());
+        }
+        // Only the bought tokens go back, into the account they came from:
+        // the pinned store vault, or a store account of the product's mint
+        let user_token = match receipt.product_id {
+            Some(_) => Self::unpack_store_owned_accounts(
+                token_program_info,
+                user_token_info,
+                store_token_info,
+            )?,
+            None => Self::unpack_token_accounts(
+                &settings,
+                token_program_info,
+                user_token_info,
+                store_token_info,
+            )?,
+        };
+        if user_token.mint.to_bytes() != receipt.mint {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        let deadline = receipt
+            .timestamp
+            .checked_add(settings.refund_window)
+            .ok_or(PriceError::Overflow)?;
+        if Clock::get()?.unix_timestamp > deadline {
+            return Err(PriceError::RefundWindowClosed.into());
+        }
+
+        invoke(
+            &Self::tokens_in_ix(
+                settings.inventory,
+                token_program_info.key,
+                user_token_info.key,
+                store_token_info.key,
+                user_info.key,
+                receipt.amount,
+            )?,
+            &[
+                user_token_info.clone(),
+                store_token_info.clone(),
+                user_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        if let Some(product_id) = receipt.product_id {
+            let product_info = next_account_info(acc_iter)?;
+            let mut product = Self::load_product(product_info, product_id)?;
+            product.stock = product
+                .stock
+                .checked_add(receipt.amount)
+                .ok_or(PriceError::Overflow)?;
+            product.serialize(&mut &mut product_info.data.borrow_mut()[..])?;
+        }
+        let recipient_info = Self::quote_recipient(&settings, user_info, acc_iter)?;
+        Self::pay_from_store(
+            store_info,
+            store_bump,
+            recipient_info,
+            token_program_info,
+            system_program_info,
+            receipt.lamports,
+        )?;
+
+        Self::move_lamports(receipt_info, user_info, receipt_info.lamports())?;
+        receipt_info.data.borrow_mut().fill(0);
+        StoreEvent::Refunded {
+            user: receipt.buyer,
+            amount: receipt.amount,
+            lamports: receipt.lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Checks that Buy delivers into the buyer's vesting escrow and adds
+    /// `amount` to the buyer's locked total.
+    fn record_vesting(
+        user_info: &AccountInfo,
+        escrow_info: &AccountInfo,
+        vesting_info: &AccountInfo,
+        amount: u64,
+    ) -> ProgramResult {
+        let (vesting_pubkey, _) = Vesting::get_vesting_pubkey(user_info.key);
+        let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(user_info.key);
+        if vesting_pubkey != *vesting_info.key || escrow_pubkey != *escrow_info.key {
+            return Err(PriceError::WrongVestingPDA.into());
+        }
+        let mut vesting = Vesting::try_from_slice(&vesting_info.data.borrow())?;
+        vesting.total = vesting.total.checked_add(amount).ok_or(PriceError::Overflow)?;
+        vesting.serialize(&mut &mut vesting_info.data.borrow_mut()[..])?;
+        StoreEvent::Vested {
+            buyer: vesting.buyer,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_set_vesting(accounts: &[AccountInfo], vesting: VestingSchedule) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        if !vesting.is_valid() {
+            return Err(PriceError::InvalidVesting.into());
+        }
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.vesting = vesting;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_set_inventory(accounts: &[AccountInfo], inventory: Inventory) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.inventory = inventory;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_set_quote(accounts: &[AccountInfo], quote: Quote) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.quote = quote;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    fn process_open_vesting(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let user_info = next_account_info(acc_iter)?;
+        let vesting_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let mint_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let (vesting_pubkey, vesting_bump) = Vesting::get_vesting_pubkey(user_info.key);
+        let (escrow_pubkey, escrow_bump) = Vesting::get_escrow_pubkey(user_info.key);
+        if vesting_pubkey != *vesting_info.key || escrow_pubkey != *escrow_info.key {
+            return Err(PriceError::WrongVestingPDA.into());
+        }
+
+        let vesting = Vesting {
+            buyer: user_info.key.to_bytes(),
+            total: 0,
+            claimed: 0,
+        };
+        let space = vesting.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let vesting_seeds: &[&[_]] = &[
+            VESTING_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &[vesting_bump],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                user_info.key,
+                &vesting_pubkey,
+                rent.minimum_balance(space),
+                space as u64,
+                &id(),
+            ),
+            &[
+                user_info.clone(),
+                vesting_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[vesting_seeds],
+        )?;
+        vesting.serialize(&mut &mut vesting_info.data.borrow_mut()[..])?;
+
+        let escrow_seeds: &[&[_]] = &[
+            VESTING_ESCROW_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &[escrow_bump],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                user_info.key,
+                &escrow_pubkey,
+                rent.minimum_balance(spl_token::state::Account::LEN),
+                spl_token::state::Account::LEN as u64,
+                &spl_token::id(),
+            ),
+            &[
+                user_info.clone(),
+                escrow_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[escrow_seeds],
+        )?;
+        invoke(
+            &spl_token::instruction::initialize_account(
+                token_program_info.key,
+                &escrow_pubkey,
+                mint_info.key,
+                &vesting_pubkey,
+            )?,
+            &[
+                escrow_info.clone(),
+                mint_info.clone(),
+                vesting_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        StoreEvent::VestingOpened {
+            buyer: user_info.key.to_bytes(),
+            vesting: vesting_pubkey.to_bytes(),
+            escrow: escrow_pubkey.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_claim(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let user_info = next_account_info(acc_iter)?;
+        let user_token_info = next_account_info(acc_iter)?;
+        let vesting_info = next_account_info(acc_iter)?;
+        let escrow_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let (vesting_pubkey, vesting_bump) = Vesting::get_vesting_pubkey(user_info.key);
+        let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(user_info.key);
+        if vesting_pubkey != *vesting_info.key || escrow_pubkey != *escrow_info.key {
+            return Err(PriceError::WrongVestingPDA.into());
+        }
+        let settings = Self::load_settings(settings_info)?;
+        let mut vesting = Vesting::try_from_slice(&vesting_info.data.borrow())?;
+        let amount = vesting.claimable(&settings.vesting, Clock::get()?.unix_timestamp);
+        vesting.claimed += amount;
+
+        let vesting_seeds: &[&[_]] = &[
+            VESTING_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &[vesting_bump],
+        ];
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program_info.key,
+                escrow_info.key,
+                user_token_info.key,
+                vesting_info.key,
+                &[],
+                amount,
+            )?,
+            &[
+                escrow_info.clone(),
+                user_token_info.clone(),
+                vesting_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[vesting_seeds],
+        )?;
+        vesting.serialize(&mut &mut vesting_info.data.borrow_mut()[..])?;
+        StoreEvent::Claimed {
+            buyer: vesting.buyer,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Account credited with the lamports of a trade: the user account, or the
+    /// user's wSOL token account, read next, while the quote is wrapped SOL.
+    fn quote_recipient<'a, 'b>(
+        settings: &Settings,
+        user_info: &'a AccountInfo<'b>,
+        acc_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        match settings.quote {
+            Quote::Native => Ok(user_info),
+            Quote::WrappedSol => {
+                let wsol_info = next_account_info(acc_iter)?;
+                Self::check_wsol_account(user_info, wsol_info)?;
+                Ok(wsol_info)
+            }
+        }
+    }
+
+    fn check_wsol_account(user_info: &AccountInfo, wsol_info: &AccountInfo) -> ProgramResult {
+        if wsol_info.owner != &spl_token::id() {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        let wsol = spl_token::state::Account::unpack(&wsol_info.data.borrow())?;
+        if wsol.mint != spl_token::native_mint::id() {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        if wsol.owner != *user_info.key {
+            return Err(TokenError::NoOwner.into());
+        }
+        Ok(())
+    }
+
+    /// Settlement of every payout from the store PDA. A wSOL recipient is
+    /// synced so the lamports show up in its token balance.
+    fn pay_from_store<'a>(
+        store_info: &AccountInfo<'a>,
+        store_bump: u8,
+        recipient_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        lamports: u64,
+    ) -> ProgramResult {
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        invoke_signed(
+            &system_instruction::transfer(store_info.key, recipient_info.key, lamports),
+            &[
+                store_info.clone(),
+                recipient_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+        if recipient_info.owner == &spl_token::id() {
+            invoke(
+                &spl_token::instruction::sync_native(token_program_info.key, recipient_info.key)?,
+                &[recipient_info.clone(), token_program_info.clone()],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Turns `lamports` of the user's wSOL into native lamports of the user
+    /// account, so a wrapped SOL payment settles like a native one. The wSOL
+    /// moves through a temporary token account that is closed to the user.
+    fn unwrap_quote<'a, 'b>(
+        user_info: &'a AccountInfo<'b>,
+        acc_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        rent_info: &AccountInfo<'b>,
+        token_program_info: &AccountInfo<'b>,
+        system_program_info: &AccountInfo<'b>,
+        lamports: u64,
+    ) -> ProgramResult {
+        let wsol_info = next_account_info(acc_iter)?;
+        let temp_info = next_account_info(acc_iter)?;
+        let mint_info = next_account_info(acc_iter)?;
+
+        Self::check_wsol_account(user_info, wsol_info)?;
+        if *mint_info.key != spl_token::native_mint::id() {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        let (temp_pubkey, temp_bump) = Store::get_wsol_pubkey(user_info.key);
+        if temp_pubkey != *temp_info.key {
+            return Err(PriceError::WrongStorePDA.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_info)?;
+        let temp_seeds: &[&[_]] = &[
+            WSOL_SEED.as_bytes(),
+            &user_info.key.to_bytes(),
+            &[temp_bump],
+        ];
+        // Lamports sent to the address beforehand are wrapped too and come
+        // back to the user when the account is closed
+        Self::create_pda_account(
+            user_info,
+            temp_info,
+            spl_token::state::Account::LEN,
+            &spl_token::id(),
+            rent,
+            system_program_info,
+            temp_seeds,
+        )?;
+        invoke(
+            &spl_token::instruction::initialize_account(
+                token_program_info.key,
+                &temp_pubkey,
+                mint_info.key,
+                user_info.key,
+            )?,
+            &[
+                temp_info.clone(),
+                mint_info.clone(),
+                user_info.clone(),
+                rent_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program_info.key,
+                wsol_info.key,
+                &temp_pubkey,
+                user_info.key,
+                &[],
+                lamports,
+            )?,
+            &[
+                wsol_info.clone(),
+                temp_info.clone(),
+                user_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+        invoke(
+            &spl_token::instruction::close_account(
+                token_program_info.key,
+                &temp_pubkey,
+                user_info.key,
+                user_info.key,
+                &[],
+            )?,
+            &[
+                temp_info.clone(),
+                user_info.clone(),
+                user_info.clone(),
+                token_program_info.clone(),
+            ],
+        )
+    }
+
+    fn process_sell(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let user_info = next_account_info(acc_iter)?;
+        let user_token_info = next_account_info(acc_iter)?;
+        let store_info = next_account_info(acc_iter)?;
+        let store_token_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        if !user_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let store_bump = Self::check_store(store_info)?;
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        // `updated_price` is stale while the auction sets the Buy price
+        if settings.price_mode == PriceMode::DutchAuction {
+            return Err(PriceError::AuctionActive.into());
+        }
+        // Only tokens of the pinned mint, returned to the pinned vault, are paid for
+        Self::unpack_token_accounts(
+            &settings,
+            token_program_info,
+            user_token_info,
+            store_token_info,
+        )?;
+        let lamports = settings.lamports_for(amount).ok_or(PriceError::Overflow)?;
+        Self::track_limits(&mut settings, amount, 0, lamports)?;
+
+        let ix = Self::tokens_in_ix(
+            settings.inventory,
+            token_program_info.key,
+            user_token_info.key,
+            store_token_info.key,
+            user_info.key,
+            amount,
+        )?;
+        invoke(
+            &ix,
+            &[
+                user_token_info.clone(),
+                store_token_info.clone(),
+                user_info.clone(),
+                token_program_info.clone(),
+            ],
+        )?;
+
+        let recipient_info = Self::quote_recipient(&settings, user_info, acc_iter)?;
+        Self::pay_from_store(
+            store_info,
+            store_bump,
+            recipient_info,
+            token_program_info,
+            system_program_info,
+            lamports,
+        )?;
+        if let Some(history_info) =
+            Self::next_price_history(&settings, HistoryMode::PriceChangesAndTrades, acc_iter)?
+        {
+            Self::record_price(history_info, settings.updated_price)?;
+        }
+        let price = settings.updated_price;
+        settings
+            .accumulators
+            .record(Side::Sell, price, amount, lamports, Clock::get()?.unix_timestamp);
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        StoreEvent::Sold {
+            user: user_info.key.to_bytes(),
+            amount,
+            lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_sell_delegated(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let store_info = next_account_info(acc_iter)?;
+        let user_token_info = next_account_info(acc_iter)?;
+        let store_token_info = next_account_info(acc_iter)?;
+        let user_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+
+        let store_bump = Self::check_store(store_info)?;
+        let mut settings = Self::load_settings(settings_info)?;
+        if settings.paused {
+            return Err(PriceError::StorePaused.into());
+        }
+        if settings.price_mode == PriceMode::DutchAuction {
+            return Err(PriceError::AuctionActive.into());
+        }
+        let user_token = Self::unpack_token_accounts(
+            &settings,
+            token_program_info,
+            user_token_info,
+            store_token_info,
+        )?;
+        if user_token.owner != *user_info.key {
+            return Err(TokenError::NoOwner.into());
+        }
+        if user_token.delegate != COption::Some(*store_info.key) {
+            return Err(TokenError::NotDelegate.into());
+        }
+        if user_token.delegated_amount < amount || user_token.amount < amount {
+            return Err(TokenError::InsufficientFunds.into());
+        }
+        let lamports = settings.lamports_for(amount).ok_or(PriceError::Overflow)?;
+        Self::track_limits(&mut settings, amount, 0, lamports)?;
+
+        let signer_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+        let ix = Self::tokens_in_ix(
+            settings.inventory,
+            token_program_info.key,
+            user_token_info.key,
+            store_token_info.key,
+            store_info.key,
+            amount,
+        )?;
+        invoke_signed(
+            &ix,
+            &[
+                user_token_info.clone(),
+                store_token_info.clone(),
+                store_info.clone(),
+                token_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+
+        let recipient_info = Self::quote_recipient(&settings, user_info, acc_iter)?;
+        Self::pay_from_store(
+            store_info,
+            store_bump,
+            recipient_info,
+            token_program_info,
+            system_program_info,
+            lamports,
+        )?;
+        let price = settings.updated_price;
+        settings
+            .accumulators
+            .record(Side::Sell, price, amount, lamports, Clock::get()?.unix_timestamp);
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        StoreEvent::Sold {
+            user: user_info.key.to_bytes(),
+            amount,
+            lamports,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_place_order(
+        accounts: &[AccountInfo],
+        order_id: u64,
+        side: Side,
+        price: u32,
+        amount: u64,
+    ) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let owner_info = next_account_info(acc_iter)?;
+        let order_info = next_account_info(acc_iter)?;
+        let owner_token_info = next_account_info(acc_iter)?;
+        let store_token_info = next_account_info(acc_iter)?;
+        let rent_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if price == 0 || amount == 0 {
+            return Err(PriceError::InvalidOrder.into());
+        }
+        let (order_pubkey, bump_seed) = Order::get_order_pubkey(owner_info.key, order_id);
+        if order_pubkey != *order_info.key {
+            return Err(PriceError::WrongOrderPDA.into());
+        }
+        let owner_token = Self::unpack_store_owned_accounts(
+            token_program_info,
+            owner_token_info,
+            store_token_info,
+        )?;
+
+        let escrow_info = match side {
+            Side::Sell => Some(next_account_info(acc_iter)?),
+            Side::Buy => None,
+        };
+        let token_account = match escrow_info {
+            Some(escrow_info) => {
+                let escrow = spl_token::state::Account::unpack(&escrow_info.data.borrow())?;
+                if escrow.owner != order_pubkey || escrow.close_authority.is_some() {
+                    return Err(TokenError::NoOwner.into());
+                }
+                if escrow.mint != owner_token.mint {
+                    return Err(TokenError::TokenMismatch.into());
+                }
+                *escrow_info.key
+            }
+            None => *owner_token_info.key,
+        };
+        let order = Order {
+            owner: owner_info.key.to_bytes(),
+            order_id,
+            side,
+            price,
+            amount,
+            token_account: token_account.to_bytes(),
+        };
+        let escrow_lamports = match side {
+            Side::Sell => 0,
+            Side::Buy => order.lamports_for(amount).ok_or(PriceError::Overflow)?,
+        };
+
+        let space = order.try_to_vec()?.len();
+        let rent = &Rent::from_account_info(rent_info)?;
+        let lamports = rent
+            .minimum_balance(space)
+            .checked_add(escrow_lamports)
+            .ok_or(PriceError::Overflow)?;
+        let order_id_bytes = order_id.to_le_bytes();
+        let signer_seeds: &[&[_]] = &[
+            ORDER_SEED.as_bytes(),
+            &owner_info.key.to_bytes(),
+            &order_id_bytes,
+            &[bump_seed],
+        ];
+        invoke_signed(
+            &system_instruction::create_account(
+                owner_info.key,
+                &order_pubkey,
+                lamports,
+                space as u64,
+                &id(),
+            ),
+            &[
+                owner_info.clone(),
+                order_info.clone(),
+                system_program_info.clone(),
+            ],
+            &[signer_seeds],
+        )?;
+
+        if let Some(escrow_info) = escrow_info {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program_info.key,
+                    owner_token_info.key,
+                    escrow_info.key,
+                    owner_info.key,
+                    &[],
+                    amount,
+                )?,
+                &[
+                    owner_token_info.clone(),
+                    escrow_info.clone(),
+                    owner_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+
+        order.serialize(&mut &mut order_info.data.borrow_mut()[..])?;
+        StoreEvent::OrderPlaced {
+            order: order_pubkey.to_bytes(),
+            owner: order.owner,
+            side,
+            price,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_cancel_order(accounts: &[AccountInfo]) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let owner_info = next_account_info(acc_iter)?;
+        let order_info = next_account_info(acc_iter)?;
+        let order_token_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let order = Self::load_order(order_info, order_token_info, owner_info)?;
+        if order.side == Side::Sell {
+            let owner_token_info = next_account_info(acc_iter)?;
+            Self::invoke_as_order(
+                &order,
+                &spl_token::instruction::transfer(
+                    token_program_info.key,
+                    order_token_info.key,
+                    owner_token_info.key,
+                    order_info.key,
+                    &[],
+                    order.amount,
+                )?,
+                &[
+                    order_token_info.clone(),
+                    owner_token_info.clone(),
+                    order_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+        Self::close_order(&order, order_info, order_token_info, owner_info, token_program_info)?;
+        StoreEvent::OrderCancelled {
+            order: order_info.key.to_bytes(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn process_fill_order(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let maker_info = next_account_info(acc_iter)?;
+        let maker_token_info = next_account_info(acc_iter)?;
+        let maker_owner_info = next_account_info(acc_iter)?;
+        let token_program_info = next_account_info(acc_iter)?;
+        let system_program_info = next_account_info(acc_iter)?;
+        let counterparty_info = next_account_info(acc_iter)?;
+        let counterparty_token_info = next_account_info(acc_iter)?;
+        let counterparty_extra_info = next_account_info(acc_iter)?;
+
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut maker = Self::load_order(maker_info, maker_token_info, maker_owner_info)?;
+        let price = maker.price;
+
+        let amount = if Store::is_pubkey_ok(counterparty_info.key) {
+            let store_bump = Self::check_store(counterparty_info)?;
+            let mut settings = Self::load_settings(counterparty_extra_info)?;
+            if settings.paused {
+                return Err(PriceError::StorePaused.into());
+            }
+            // As with Sell, the store doesn't buy back while the auction sets its price
+            if maker.side == Side::Sell && settings.price_mode == PriceMode::DutchAuction {
+                return Err(PriceError::AuctionActive.into());
+            }
+            // Sold tokens must land in the store's own token account
+            Self::unpack_token_accounts(
+                &settings,
+                token_program_info,
+                maker_token_info,
+                counterparty_token_info,
+            )?;
+            let store_price = settings.buy_price_at(Clock::get()?.unix_timestamp);
+            let crosses = match maker.side {
+                Side::Sell => price <= store_price,
+                Side::Buy => price >= store_price,
+            };
+            if !crosses {
+                return Err(PriceError::OrderNotCrossing.into());
+            }
+            let amount = amount.min(maker.amount);
+            let lamports = maker.lamports_for(amount).ok_or(PriceError::Overflow)?;
+            match maker.side {
+                Side::Sell => Self::track_limits(&mut settings, amount, 0, lamports)?,
+                Side::Buy => Self::track_limits(&mut settings, amount, amount, 0)?,
+            }
+            // The order side is the user's, as for Buy and Sell
+            settings
+                .accumulators
+                .record(maker.side, price, amount, lamports, Clock::get()?.unix_timestamp);
+            settings.serialize(&mut &mut counterparty_extra_info.data.borrow_mut()[..])?;
+
+            let store_seeds: &[&[_]] = &[STORE_SEED.as_bytes(), &[store_bump]];
+            match maker.side {
+                Side::Sell => {
+                    Self::invoke_as_order(
+                        &maker,
+                        &Self::tokens_in_ix(
+                            settings.inventory,
+                            token_program_info.key,
+                            maker_token_info.key,
+                            counterparty_token_info.key,
+                            maker_info.key,
+                            amount,
+                        )?,
+                        &[
+                            maker_token_info.clone(),
+                            counterparty_token_info.clone(),
+                            maker_info.clone(),
+                            token_program_info.clone(),
+                        ],
+                    )?;
+                    invoke_signed(
+                        &system_instruction::transfer(
+                            counterparty_info.key,
+                            maker_owner_info.key,
+                            lamports,
+                        ),
+                        &[
+                            counterparty_info.clone(),
+                            maker_owner_info.clone(),
+                            system_program_info.clone(),
+                        ],
+                        &[store_seeds],
+                    )?;
+                }
+                Side::Buy => {
+                    invoke_signed(
+                        &Self::tokens_out_ix(
+                            settings.inventory,
+                            token_program_info.key,
+                            counterparty_token_info.key,
+                            maker_token_info.key,
+                            counterparty_info.key,
+                            amount,
+                        )?,
+                        &[
+                            counterparty_token_info.clone(),
+                            maker_token_info.clone(),
+                            counterparty_info.clone(),
+                            token_program_info.clone(),
+                        ],
+                        &[store_seeds],
+                    )?;
+                    Self::move_lamports(maker_info, counterparty_info, lamports)?;
+                }
+            }
+            amount
+        } else {
+            let mut taker = Self::load_order(
+                counterparty_info,
+                counterparty_token_info,
+                counterparty_extra_info,
+            )?;
+            let crosses = match maker.side {
+                Side::Sell => taker.side == Side::Buy && taker.price >= price,
+                Side::Buy => taker.side == Side::Sell && taker.price <= price,
+            };
+            if !crosses {
+                return Err(PriceError::OrderNotCrossing.into());
+            }
+            let amount = amount.min(maker.amount).min(taker.amount);
+            let lamports = maker.lamports_for(amount).ok_or(PriceError::Overflow)?;
+
+            let (seller, seller_info, seller_token_info, seller_owner_info, buyer_info, buyer_token_info) =
+                match maker.side {
+                    Side::Sell => (
+                        &maker,
+                        maker_info,
+                        maker_token_info,
+                        maker_owner_info,
+                        counterparty_info,
+                        counterparty_token_info,
+                    ),
+                    Side::Buy => (
+                        &taker,
+                        counterparty_info,
+                        counterparty_token_info,
+                        counterparty_extra_info,
+                        maker_info,
+                        maker_token_info,
+                    ),
+                };
+            Self::invoke_as_order(
+                seller,
+                &spl_token::instruction::transfer(
+                    token_program_info.key,
+                    seller_token_info.key,
+                    buyer_token_info.key,
+                    seller_info.key,
+                    &[],
+                    amount,
+                )?,
+                &[
+                    seller_token_info.clone(),
+                    buyer_token_info.clone(),
+                    seller_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+            Self::move_lamports(buyer_info, seller_owner_info, lamports)?;
+
+            taker.amount -= amount;
+            Self::save_or_close_order(
+                &taker,
+                counterparty_info,
+                counterparty_token_info,
+                counterparty_extra_info,
+                token_program_info,
+            )?;
+            amount
+        };
+
+        maker.amount -= amount;
+        Self::save_or_close_order(
+            &maker,
+            maker_info,
+            maker_token_info,
+            maker_owner_info,
+            token_program_info,
+        )?;
+        StoreEvent::OrderFilled {
+            order: maker_info.key.to_bytes(),
+            counterparty: counterparty_info.key.to_bytes(),
+            price,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Loads an order, checking its PDA and that the passed token account and
+    /// owner are the ones recorded in it.
+    fn load_order(
+        order_info: &AccountInfo,
+        order_token_info: &AccountInfo,
+        owner_info: &AccountInfo,
+    ) -> Result<Order, ProgramError> {
+        if order_info.owner != &id() {
+            return Err(PriceError::WrongOrderPDA.into());
+        }
+        let order = Order::try_from_slice(&order_info.data.borrow())?;
+        let owner = Pubkey::new_from_array(order.owner);
+        if !Order::is_pubkey_ok(&owner, order.order_id, order_info.key)
+            || owner != *owner_info.key
+            || order.token_account != order_token_info.key.to_bytes()
+        {
+            return Err(PriceError::WrongOrderPDA.into());
+        }
+        Ok(order)
+    }
+
+    fn invoke_as_order(
+        order: &Order,
+        instruction: &solana_program::instruction::Instruction,
+        account_infos: &[AccountInfo],
+    ) -> ProgramResult {
+        let (_, bump_seed) = Order::get_order_pubkey(&Pubkey::new_from_array(order.owner), order.order_id);
+        let order_id_bytes = order.order_id.to_le_bytes();
+        let signer_seeds: &[&[_]] = &[
+            ORDER_SEED.as_bytes(),
+            &order.owner,
+            &order_id_bytes,
+            &[bump_seed],
+        ];
+        invoke_signed(instruction, account_infos, &[signer_seeds])
+    }
+
+    fn save_or_close_order<'a>(
+        order: &Order,
+        order_info: &AccountInfo<'a>,
+        order_token_info: &AccountInfo<'a>,
+        owner_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        if order.amount == 0 {
+            return Self::close_order(order, order_info, order_token_info, owner_info, token_program_info);
+        }
+        order.serialize(&mut &mut order_info.data.borrow_mut()[..])?;
+        Ok(())
+    }
+
+    /// Closes the escrow token account of a sell order and returns every
+    /// lamport left in the order PDA to its owner.
+    fn close_order<'a>(
+        order: &Order,
+        order_info: &AccountInfo<'a>,
+        order_token_info: &AccountInfo<'a>,
+        owner_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        if order.side == Side::Sell {
+            Self::invoke_as_order(
+                order,
+                &spl_token::instruction::close_account(
+                    token_program_info.key,
+                    order_token_info.key,
+                    owner_info.key,
+                    order_info.key,
+                    &[],
+                )?,
+                &[
+                    order_token_info.clone(),
+                    owner_info.clone(),
+                    order_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+        Self::move_lamports(order_info, owner_info, order_info.lamports())?;
+        order_info.data.borrow_mut().fill(0);
+        Ok(())
+    }
+
+    /// Moves lamports out of an account owned by this program.
+    fn move_lamports(from_info: &AccountInfo, to_info: &AccountInfo, lamports: u64) -> ProgramResult {
+        **from_info.try_borrow_mut_lamports()? = from_info
+            .lamports()
+            .checked_sub(lamports)
+            .ok_or(TokenError::InsufficientFunds)?;
+        **to_info.try_borrow_mut_lamports()? = to_info
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(PriceError::Overflow)?;
+        Ok(())
+    }
+
+    /// Amount a Buy of `requested` tokens fills out of `available`: all of it,
+    /// or what is available when partial fills are allowed. Applied in turn to
+    /// the store token account, then the product stock or listing amount.
+    fn fill_amount(
+        requested: u64,
+        available: u64,
+        allow_partial: bool,
+    ) -> Result<u64, ProgramError> {
+        if requested <= available {
+            Ok(requested)
+        } else if allow_partial && available > 0 {
+            Ok(available)
+        } else {
+            Err(TokenError::InsufficientFunds.into())
+        }
+    }
+
+    fn load_settings(settings_info: &AccountInfo) -> Result<Settings, ProgramError> {
+        if !Settings::is_pubkey_ok(settings_info.key) {
+            return Err(PriceError::WrongSettingsPDA.into());
+        }
+        Ok(Settings::try_from_slice(&settings_info.data.borrow())?)
+    }
+
+    /// Writes `settings` over the settings account and logs the fields that changed.
+    fn save_settings(settings_info: &AccountInfo, settings: &Settings) -> ProgramResult {
+        let old = Settings::try_from_slice(&settings_info.data.borrow())?;
+        settings.serialize(&mut &mut settings_info.data.borrow_mut()[..])?;
+        StoreEvent::settings_updated(&old, settings).emit();
+        Ok(())
+    }
+
+    /// Applies the trade limits to a trade, pausing the store once a cap of the
+    /// current window is reached. The trade reaching the cap still goes through
+    /// so the pause is persisted with it, one going past the cap is rejected.
+    fn track_limits(
+        settings: &mut Settings,
+        amount: u64,
+        tokens_sold: u64,
+        lamports_paid: u64,
+    ) -> ProgramResult {
+        let clock = Clock::get()?;
+        let capped = settings.track_trade(
+            amount,
+            tokens_sold,
+            lamports_paid,
+            clock.epoch,
+            clock.unix_timestamp,
+        )?;
+        if capped && !settings.paused {
+            settings.paused = true;
+            StoreEvent::CircuitTripped {
+                window: settings.window.id,
+                tokens_sold: settings.window.tokens_sold,
+                lamports_paid: settings.window.lamports_paid,
+            }
+            .emit();
+        }
+        Ok(())
+    }
+
+    fn process_set_limits(accounts: &[AccountInfo], limits: TradeLimits) -> ProgramResult {
+        let acc_iter = &mut accounts.iter();
+        let admin_info = next_account_info(acc_iter)?;
+        let settings_info = next_account_info(acc_iter)?;
+
+        let mut settings = Self::load_settings(settings_info)?;
+        Self::validate_authority(&settings.admin, admin_info, acc_iter.as_slice())?;
+        settings.limits = limits;
+        Self::save_settings(settings_info, &settings)?;
+        Ok(())
+    }
+
+    /// Returns the bump seed of the store PDA.
+    fn check_store(store_info: &AccountInfo) -> Result<u8, ProgramError> {
+        let (store_pubkey, store_bump) = Store::get_store_pubkey();
+        if store_pubkey != *store_info.key {
+            return Err(PriceError::WrongStorePDA.into());
+        }
+        Ok(store_bump)
+    }
+
+    /// Unpacks the user and store token accounts, checking that both hold the
+    /// same mint and that the store token account belongs to the store PDA.
+    /// Unpacks the user's token account, checking it against the store token
+    /// account pinned in settings: the store vault, or the mint in
+    /// mint-on-demand mode.
+    fn unpack_token_accounts(
+        settings: &Settings,
+        token_program_info: &AccountInfo,
+        user_token_info: &AccountInfo,
+        store_token_info: &AccountInfo,
+    ) -> Result<spl_token::state::Account, ProgramError> {
+        if store_token_info.key.to_bytes() != settings.store_token() {
+            return Err(PriceError::WrongStoreVault.into());
+        }
+        Self::unpack_store_owned_accounts(token_program_info, user_token_info, store_token_info)
+    }
+
+    /// Unpacks the user's token account, checking it holds the mint of a token
+    /// account owned by the store, or of a mint the store mints on demand.
+    fn unpack_store_owned_accounts(
+        token_program_info: &AccountInfo,
+        user_token_info: &AccountInfo,
+        store_token_info: &AccountInfo,
+    ) -> Result<spl_token::state::Account, ProgramError> {
+        if *token_program_info.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let user_token = spl_token::state::Account::unpack(&user_token_info.data.borrow())?;
+        // In mint-on-demand mode the store token account slot holds the mint itself
+        let mint = if store_token_info.data_len() == spl_token::state::Mint::LEN {
+            let mint = spl_token::state::Mint::unpack(&store_token_info.data.borrow())?;
+            if mint.mint_authority != COption::Some(Store::get_store_pub()) {
+                return Err(TokenError::NoOwner.into());
+            }
+            *store_token_info.key
+        } else {
+            let store_token = spl_token::state::Account::unpack(&store_token_info.data.borrow())?;
+            if store_token.owner != Store::get_store_pub() {
+                return Err(TokenError::NoOwner.into());
+            }
+            store_token.mint
+        };
+        if user_token.mint != mint {
+            return Err(TokenError::TokenMismatch.into());
+        }
+        Ok(user_token)
+    }
+
+    /// Moves tokens from the store to `destination`: out of the store token
+    /// account, or minted in mint-on-demand mode. Signed by the store PDA.
+    fn tokens_out_ix(
+        inventory: Inventory,
+        token_program: &Pubkey,
+        store_token: &Pubkey,
+        destination: &Pubkey,
+        store: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        match inventory {
+            Inventory::Vault => spl_token::instruction::transfer(
+                token_program, store_token, destination, store, &[], amount,
+            ),
+            Inventory::MintOnDemand => spl_token::instruction::mint_to(
+                token_program, store_token, destination, store, &[], amount,
+            ),
+        }
+    }
+
+    /// Moves tokens from `source` to the store: into the store token account,
+    /// or burned in mint-on-demand mode. Signed by `authority`, the owner or
+    /// delegate of `source`.
+    fn tokens_in_ix(
+        inventory: Inventory,
+        token_program: &Pubkey,
+        source: &Pubkey,
+        store_token: &Pubkey,
+        authority: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        match inventory {
+            Inventory::Vault => spl_token::instruction::transfer(
+                token_program, source, store_token, authority, &[], amount,
+            ),
+            Inventory::MintOnDemand => spl_token::instruction::burn(
+                token_program, source, store_token, authority, &[], amount,
+            ),
+        }
+    }
+}
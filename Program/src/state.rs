@@ -1,7 +1,14 @@
+use std::convert::TryInto;
+
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::pubkey::Pubkey;
 
-use crate::{id, PRICE_SEED, SETTINGS_SEED};
+use crate::error::PriceError;
+use crate::{
+    id, ESCROW_SEED, INVOICE_SEED, LISTING_SEED, MULTISIG_SEED, ORDER_SEED, PRICE_HISTORY_SEED,
+    PRICE_SEED, PRODUCT_SEED, RECEIPT_SEED, REFERRAL_SEED, SETTINGS_SEED, STORE_SEED,
+    SUBSCRIPTION_SEED, VESTING_ESCROW_SEED, VESTING_SEED, VOUCHER_SEED, WSOL_SEED,
+};
 
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
@@ -20,13 +27,291 @@ impl Price {
     }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PriceMode {
+    /// Buy pays `updated_price`
+    Fixed,
+    /// Buy pays the price of `Settings.auction` at the current `Clock` time
+    DutchAuction,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Inventory {
+    /// Buy and Sell move tokens out of and into the store token account
+    Vault,
+    /// The store PDA is the mint authority: Buy mints and Sell burns
+    MintOnDemand,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Quote {
+    /// Trades settle in lamports of the user account
+    Native,
+    /// Trades settle through the user's wrapped SOL token account
+    WrappedSol,
+}
+
+/// Running totals kept for other programs reading the store as a price
+/// reference. The TWAP between two snapshots is the change of
+/// `price_cumulative_at` divided by the seconds between them.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Accumulators {
+    /// Sum of each traded price times the seconds it stood, up to `last_ts`
+    pub price_cumulative: u128,
+    pub last_price: u32,
+    pub last_ts: i64,
+    /// Tokens bought from the store
+    pub bought: u128,
+    /// Tokens sold to the store
+    pub sold: u128,
+    /// Lamports paid in and out over all trades
+    pub lamports: u128,
+}
+
+impl Accumulators {
+    pub fn price_cumulative_at(&self, timestamp: i64) -> u128 {
+        let elapsed = timestamp.saturating_sub(self.last_ts).max(0) as u128;
+        self.price_cumulative
+            .saturating_add(elapsed.saturating_mul(self.last_price as u128))
+    }
+
+    /// Folds a trade of `amount` tokens for `lamports` at `price` into the totals.
+    pub fn record(&mut self, side: Side, price: u32, amount: u64, lamports: u64, timestamp: i64) {
+        if self.last_ts != 0 {
+            self.price_cumulative = self.price_cumulative_at(timestamp);
+        }
+        self.last_price = price;
+        self.last_ts = timestamp;
+        match side {
+            Side::Buy => self.bought = self.bought.saturating_add(amount as u128),
+            Side::Sell => self.sold = self.sold.saturating_add(amount as u128),
+        }
+        self.lamports = self.lamports.saturating_add(lamports as u128);
+    }
+
+    /// Average price between two snapshots, `None` when no time passed.
+    pub fn twap(start_cumulative: u128, start_ts: i64, end_cumulative: u128, end_ts: i64) -> Option<u128> {
+        let elapsed = end_ts.checked_sub(start_ts).filter(|elapsed| *elapsed > 0)? as u128;
+        Some(end_cumulative.checked_sub(start_cumulative)? / elapsed)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LimitPeriod {
+    Epoch,
+    Day,
+}
+
+/// Circuit breaker limits, 0 disables a limit. Reaching a cap pauses the store,
+/// trades that would go past it are rejected.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TradeLimits {
+    pub period: LimitPeriod,
+    /// Largest token amount of a single trade
+    pub max_trade: u64,
+    /// Tokens the store may sell per period
+    pub max_tokens_sold: u64,
+    /// Lamports the store may pay out for tokens per period
+    pub max_lamports_paid: u64,
+}
+
+impl Default for TradeLimits {
+    fn default() -> Self {
+        TradeLimits {
+            period: LimitPeriod::Day,
+            max_trade: 0,
+            max_tokens_sold: 0,
+            max_lamports_paid: 0,
+        }
+    }
+}
+
+impl TradeLimits {
+    pub fn window_id(&self, epoch: u64, timestamp: i64) -> u64 {
+        match self.period {
+            LimitPeriod::Epoch => epoch,
+            LimitPeriod::Day => (timestamp.max(0) / 86_400) as u64,
+        }
+    }
+}
+
+/// Totals of the current limit period.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct LimitWindow {
+    pub id: u64,
+    pub tokens_sold: u64,
+    pub lamports_paid: u64,
+}
+
+/// Admin and price change applied by `ExecuteSettingsChange` once its ETA passed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct SettingsChange {
+    pub admin: [u8; 32],
+    pub updated_price: u32,
+    pub timelock_delay: i64,
+    /// Product account whose price is set to `product_price`, all zeroes for none
+    pub product: [u8; 32],
+    pub product_price: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HistoryMode {
+    /// Nothing is appended to the price history
+    Off,
+    /// Price changes by `UpdateSettings` and `UpdatePrice` are appended
+    PriceChanges,
+    /// Price changes and the price of every Buy and Sell are appended
+    PriceChangesAndTrades,
+}
+
+/// Price falling linearly from `start_price` at `start_ts` to `floor_price`
+/// at `end_ts`, both unix timestamps.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct DutchAuction {
+    pub start_price: u32,
+    pub floor_price: u32,
+    pub start_ts: i64,
+    pub end_ts: i64,
+}
+
+impl DutchAuction {
+    pub fn is_valid(&self) -> bool {
+        self.start_price >= self.floor_price && self.end_ts > self.start_ts
+    }
+
+    pub fn price_at(&self, timestamp: i64) -> u32 {
+        if timestamp <= self.start_ts {
+            return self.start_price;
+        }
+        if timestamp >= self.end_ts {
+            return self.floor_price;
+        }
+        // Timestamps may span the whole i64 range, their differences only fit in u128
+        let drop = self.start_price.saturating_sub(self.floor_price) as u128;
+        let elapsed = (timestamp as i128 - self.start_ts as i128) as u128;
+        let duration = (self.end_ts as i128 - self.start_ts as i128) as u128;
+        drop.checked_mul(elapsed)
+            .and_then(|fall| fall.checked_div(duration))
+            .map_or(self.floor_price, |fall| self.start_price - fall as u32)
+    }
+}
+
+/// Unlock schedule for tokens bought while vesting is enabled: nothing before
+/// `cliff_ts`, then linear from `start_ts` to `end_ts`. Disabled when `end_ts` is 0.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct VestingSchedule {
+    pub start_ts: i64,
+    pub cliff_ts: i64,
+    pub end_ts: i64,
+}
+
+impl VestingSchedule {
+    pub fn is_enabled(&self) -> bool {
+        self.end_ts != 0
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.is_enabled()
+            || (self.start_ts <= self.cliff_ts
+                && self.cliff_ts <= self.end_ts
+                && self.start_ts < self.end_ts)
+    }
+
+    /// Part of `total` unlocked at `timestamp`.
+    pub fn vested(&self, total: u64, timestamp: i64) -> u64 {
+        if timestamp < self.cliff_ts {
+            return 0;
+        }
+        if timestamp >= self.end_ts {
+            return total;
+        }
+        let elapsed = (timestamp - self.start_ts) as u128;
+        let duration = (self.end_ts - self.start_ts) as u128;
+        (total as u128 * elapsed / duration) as u64
+    }
+}
+
+/// Basis points in 100%
+pub const MAX_BPS: u16 = 10_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, PartialEq)]
 pub struct Settings {
     pub admin: [u8; 32],
     pub updated_price: u32,
+    /// May only change `updated_price`
+    pub price_manager: [u8; 32],
+    /// May only withdraw lamports from the store PDA
+    pub treasurer: [u8; 32],
+    /// May only pause and unpause trading
+    pub pauser: [u8; 32],
+    pub paused: bool,
+    /// Share of each purchase paid to the referrer, in basis points
+    pub referral_bps: u16,
+    pub price_mode: PriceMode,
+    pub auction: DutchAuction,
+    /// Seconds after a purchase during which it can be refunded, 0 disables receipts
+    pub refund_window: i64,
+    /// When enabled Buy deposits tokens into the buyer's vesting escrow
+    pub vesting: VestingSchedule,
+    pub inventory: Inventory,
+    pub quote: Quote,
+    pub history: HistoryMode,
+    /// Updated by every Buy and Sell
+    pub accumulators: Accumulators,
+    pub limits: TradeLimits,
+    pub window: LimitWindow,
+    /// Seconds a queued change waits before it can be executed. While non-zero,
+    /// price, product price and admin changes must be queued and products can't be added.
+    pub timelock_delay: i64,
+    pub pending: SettingsChange,
+    /// Time the pending change can be executed from, 0 when nothing is queued
+    pub pending_eta: i64,
+    /// Share of each listing sale kept by the store, in basis points
+    pub commission_bps: u16,
+    pub subscription: SubscriptionTerms,
+    /// Seconds after delivery before an escrow releases itself, 0 disables escrowed purchases
+    pub escrow_timeout: i64,
+    /// How `Distribute` pays out the treasury
+    pub revenue_split: RevenueSplit,
+    /// Mint the store trades, pinned by `MigrateSettings`
+    pub store_mint: [u8; 32],
+    /// Store token account holding the inventory, pinned by `MigrateSettings`
+    pub store_vault: [u8; 32],
 }
 
 impl Settings {
+    /// Settings owned by `admin`, who also holds every role until rotated.
+    pub fn new(admin: [u8; 32], updated_price: u32) -> Self {
+        Settings {
+            admin,
+            updated_price,
+            price_manager: admin,
+            treasurer: admin,
+            pauser: admin,
+            paused: false,
+            referral_bps: 0,
+            price_mode: PriceMode::Fixed,
+            auction: DutchAuction::default(),
+            refund_window: 0,
+            vesting: VestingSchedule::default(),
+            inventory: Inventory::Vault,
+            quote: Quote::Native,
+            history: HistoryMode::Off,
+            accumulators: Accumulators::default(),
+            limits: TradeLimits::default(),
+            window: LimitWindow::default(),
+            timelock_delay: 0,
+            pending: SettingsChange::default(),
+            pending_eta: 0,
+            commission_bps: 0,
+            subscription: SubscriptionTerms::default(),
+            escrow_timeout: 0,
+            revenue_split: RevenueSplit::default(),
+            store_mint: [0; 32],
+            store_vault: [0; 32],
+        }
+    }
+
     pub fn get_settings_pubkey() -> (Pubkey, u8) {
         Pubkey::find_program_address(&[SETTINGS_SEED.as_bytes()], &id())
     }
@@ -40,4 +325,669 @@ impl Settings {
         let (pubkey, _) = Self::get_settings_pubkey();
         pubkey.to_bytes() == settings_pubkey.to_bytes()
     }
+
+    /// Reads settings written by any earlier version of the program. Fields
+    /// were only ever appended, so the missing tail takes the defaults of
+    /// `Settings::new`. Returns `None` for data that isn't settings.
+    pub fn migrate(data: &[u8]) -> Option<Self> {
+        let admin: [u8; 32] = data.get(..32)?.try_into().ok()?;
+        let updated_price = u32::from_le_bytes(data.get(32..36)?.try_into().ok()?);
+        let mut bytes = Settings::new(admin, updated_price).try_to_vec().ok()?;
+        if data.len() > bytes.len() {
+            return None;
+        }
+        bytes[..data.len()].copy_from_slice(data);
+        Self::try_from_slice(&bytes).ok()
+    }
+
+    /// Account the store's tokens move through: the pinned vault, or the
+    /// pinned mint itself in mint-on-demand mode.
+    pub fn store_token(&self) -> [u8; 32] {
+        match self.inventory {
+            Inventory::Vault => self.store_vault,
+            Inventory::MintOnDemand => self.store_mint,
+        }
+    }
+
+    pub fn lamports_for(&self, amount: u64) -> Option<u64> {
+        amount.checked_mul(self.updated_price as u64)
+    }
+
+    /// Price Buy charges per token at `timestamp`.
+    pub fn buy_price_at(&self, timestamp: i64) -> u32 {
+        match self.price_mode {
+            PriceMode::Fixed => self.updated_price,
+            PriceMode::DutchAuction => self.auction.price_at(timestamp),
+        }
+    }
+
+    pub fn buy_lamports_for(&self, amount: u64, timestamp: i64) -> Option<u64> {
+        amount.checked_mul(self.buy_price_at(timestamp) as u64)
+    }
+
+    /// Counts a trade against the current limit window, starting a new window
+    /// when the period rolled over. Fails when the trade would go past a cap,
+    /// otherwise returns whether a cap has been reached.
+    pub fn track_trade(
+        &mut self,
+        amount: u64,
+        tokens_sold: u64,
+        lamports_paid: u64,
+        epoch: u64,
+        timestamp: i64,
+    ) -> Result<bool, PriceError> {
+        let limits = self.limits;
+        if limits.max_trade != 0 && amount > limits.max_trade {
+            return Err(PriceError::TradeTooLarge);
+        }
+        let id = limits.window_id(epoch, timestamp);
+        if self.window.id != id {
+            self.window = LimitWindow {
+                id,
+                ..LimitWindow::default()
+            };
+        }
+        self.window.tokens_sold = self
+            .window
+            .tokens_sold
+            .checked_add(tokens_sold)
+            .ok_or(PriceError::Overflow)?;
+        self.window.lamports_paid = self
+            .window
+            .lamports_paid
+            .checked_add(lamports_paid)
+            .ok_or(PriceError::Overflow)?;
+        if (limits.max_tokens_sold != 0 && self.window.tokens_sold > limits.max_tokens_sold)
+            || (limits.max_lamports_paid != 0
+                && self.window.lamports_paid > limits.max_lamports_paid)
+        {
+            return Err(PriceError::TradeLimitExceeded);
+        }
+        Ok((limits.max_tokens_sold != 0 && self.window.tokens_sold >= limits.max_tokens_sold)
+            || (limits.max_lamports_paid != 0
+                && self.window.lamports_paid >= limits.max_lamports_paid))
+    }
+
+    pub fn commission(&self, lamports: u64) -> u64 {
+        (lamports as u128 * self.commission_bps as u128 / MAX_BPS as u128) as u64
+    }
+
+    pub fn referral_share(&self, lamports: u64) -> u64 {
+        (lamports as u128 * self.referral_bps as u128 / MAX_BPS as u128) as u64
+    }
+}
+
+/// Store authority PDA. Owns the store token account, receives token
+/// delegations from sellers and holds the lamports paid out on sells.
+pub struct Store;
+
+impl Store {
+    pub fn get_store_pubkey() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[STORE_SEED.as_bytes()], &id())
+    }
+
+    pub fn get_store_pub() -> Pubkey {
+        let (pubkey, _) = Self::get_store_pubkey();
+        pubkey
+    }
+
+    pub fn is_pubkey_ok(store_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_store_pubkey();
+        pubkey.to_bytes() == store_pubkey.to_bytes()
+    }
+
+    /// Temporary wSOL account a buyer unwraps their payment through
+    pub fn get_wsol_pubkey(user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[WSOL_SEED.as_bytes(), &user.to_bytes()], &id())
+    }
+}
+
+/// Maximum number of keys a multisig admin can hold.
+pub const MAX_SIGNERS: usize = 11;
+
+/// M-of-N admin account. `Settings.admin` may point at one of these
+/// instead of a single key.
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct Multisig {
+    pub threshold: u8,
+    pub signers: Vec<[u8; 32]>,
+}
+
+impl Multisig {
+    pub fn get_multisig_pubkey(creator: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[MULTISIG_SEED.as_bytes(), &creator.to_bytes()], &id())
+    }
+
+    pub fn is_pubkey_ok(creator: &Pubkey, multisig_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_multisig_pubkey(creator);
+        pubkey.to_bytes() == multisig_pubkey.to_bytes()
+    }
+
+    /// At most `MAX_SIGNERS` distinct keys and a threshold they can reach.
+    pub fn is_valid(&self) -> bool {
+        let distinct = self
+            .signers
+            .iter()
+            .enumerate()
+            .all(|(i, signer)| !self.signers[..i].contains(signer));
+        distinct
+            && self.threshold > 0
+            && self.signers.len() <= MAX_SIGNERS
+            && self.threshold as usize <= self.signers.len()
+    }
+
+    /// Number of distinct multisig keys among the signed accounts.
+    pub fn count_signers(&self, signed: &[Pubkey]) -> u8 {
+        let mut matched = [false; MAX_SIGNERS];
+        for key in signed {
+            if let Some(position) = self
+                .signers
+                .iter()
+                .position(|signer| *signer == key.to_bytes())
+            {
+                matched[position] = true;
+            }
+        }
+        matched.iter().filter(|m| **m).count() as u8
+    }
 }
+
+/// Claimable referral rewards and running totals for one referrer.
+/// Rewards are held as lamports of this account until claimed.
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct Referral {
+    pub referrer: [u8; 32],
+    pub purchases: u64,
+    pub total_earned: u64,
+    pub total_claimed: u64,
+}
+
+impl Referral {
+    pub fn get_referral_pubkey(referrer: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[REFERRAL_SEED.as_bytes(), &referrer.to_bytes()], &id())
+    }
+
+    pub fn is_pubkey_ok(referrer: &Pubkey, referral_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_referral_pubkey(referrer);
+        pubkey.to_bytes() == referral_pubkey.to_bytes()
+    }
+
+    pub fn claimable(&self) -> u64 {
+        self.total_earned - self.total_claimed
+    }
+}
+
+/// Marker account, owned by the program once the buyer redeemed the voucher nonce.
+pub struct VoucherNonce;
+
+impl VoucherNonce {
+    pub fn get_nonce_pubkey(buyer: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[VOUCHER_SEED.as_bytes(), &buyer.to_bytes(), &nonce.to_le_bytes()],
+            &id(),
+        )
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Resting limit order. Sell orders escrow tokens in a token account owned by
+/// the order PDA, buy orders escrow `price * amount` lamports in the PDA itself.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Order {
+    pub owner: [u8; 32],
+    pub order_id: u64,
+    pub side: Side,
+    /// Lamports per token, same unit as `Settings.updated_price`
+    pub price: u32,
+    /// Tokens left to fill
+    pub amount: u64,
+    /// Escrow token account for sell orders, the owner's receiving token
+    /// account for buy orders
+    pub token_account: [u8; 32],
+}
+
+impl Order {
+    pub fn get_order_pubkey(owner: &Pubkey, order_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[ORDER_SEED.as_bytes(), &owner.to_bytes(), &order_id.to_le_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(owner: &Pubkey, order_id: u64, order_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_order_pubkey(owner, order_id);
+        pubkey.to_bytes() == order_pubkey.to_bytes()
+    }
+
+    pub fn lamports_for(&self, amount: u64) -> Option<u64> {
+        amount.checked_mul(self.price as u64)
+    }
+}
+
+/// Record of one purchase, kept while it can still be refunded.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub buyer: [u8; 32],
+    pub receipt_id: u64,
+    pub amount: u64,
+    /// Lamports the store received, paid back in full by a refund. Any
+    /// referral share went to the referrer and is not refunded.
+    pub lamports: u64,
+    pub timestamp: i64,
+    /// The bought mint, the only one a refund takes back
+    pub mint: [u8; 32],
+    /// The catalog product bought, restocked by a refund
+    pub product_id: Option<u64>,
+}
+
+impl Receipt {
+    pub fn get_receipt_pubkey(buyer: &Pubkey, receipt_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[RECEIPT_SEED.as_bytes(), &buyer.to_bytes(), &receipt_id.to_le_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(buyer: &Pubkey, receipt_id: u64, receipt_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_receipt_pubkey(buyer, receipt_id);
+        pubkey.to_bytes() == receipt_pubkey.to_bytes()
+    }
+}
+
+/// Tokens a buyer bought while vesting was enabled. They are held in the
+/// buyer's escrow token account, owned by this PDA, until claimed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Vesting {
+    pub buyer: [u8; 32],
+    pub total: u64,
+    pub claimed: u64,
+}
+
+impl Vesting {
+    pub fn get_vesting_pubkey(buyer: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[VESTING_SEED.as_bytes(), &buyer.to_bytes()], &id())
+    }
+
+    pub fn get_escrow_pubkey(buyer: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[VESTING_ESCROW_SEED.as_bytes(), &buyer.to_bytes()], &id())
+    }
+
+    pub fn claimable(&self, schedule: &VestingSchedule, timestamp: i64) -> u64 {
+        schedule.vested(self.total, timestamp).saturating_sub(self.claimed)
+    }
+}
+
+/// Number of entries kept by the price history before the oldest is overwritten.
+pub const PRICE_HISTORY_LEN: usize = 64;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct PriceEntry {
+    pub slot: u64,
+    pub timestamp: i64,
+    pub price: u32,
+}
+
+/// Ring buffer of the store's last `PRICE_HISTORY_LEN` prices. `entries` always
+/// holds `PRICE_HISTORY_LEN` items so the account size never changes.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct PriceHistory {
+    /// Index the next entry is written to
+    pub head: u32,
+    /// Number of entries written, up to `PRICE_HISTORY_LEN`
+    pub len: u32,
+    pub entries: Vec<PriceEntry>,
+}
+
+impl Default for PriceHistory {
+    fn default() -> Self {
+        PriceHistory {
+            head: 0,
+            len: 0,
+            entries: vec![PriceEntry::default(); PRICE_HISTORY_LEN],
+        }
+    }
+}
+
+impl PriceHistory {
+    pub fn get_history_pubkey() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[PRICE_HISTORY_SEED.as_bytes()], &id())
+    }
+
+    pub fn is_pubkey_ok(history_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_history_pubkey();
+        pubkey.to_bytes() == history_pubkey.to_bytes()
+    }
+
+    /// Appends `entry`, overwriting the oldest one once the buffer is full.
+    pub fn push(&mut self, entry: PriceEntry) {
+        self.entries[self.head as usize] = entry;
+        self.head = (self.head + 1) % PRICE_HISTORY_LEN as u32;
+        self.len = (self.len + 1).min(PRICE_HISTORY_LEN as u32);
+    }
+
+    /// Written entries, oldest first.
+    pub fn chronological(&self) -> Vec<PriceEntry> {
+        let start = if (self.len as usize) < PRICE_HISTORY_LEN {
+            0
+        } else {
+            self.head as usize
+        };
+        (0..self.len as usize)
+            .map(|i| self.entries[(start + i) % PRICE_HISTORY_LEN])
+            .collect()
+    }
+}
+
+/// What `ChargeSubscription` takes from every subscriber each period.
+/// A `period` of 0 closes subscriptions.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct SubscriptionTerms {
+    /// Lamports, or tokens of `payment_mint` for delegated subscriptions
+    pub price: u64,
+    /// Seconds between charges
+    pub period: i64,
+    pub payment_mint: [u8; 32],
+    /// Store tokens delivered on every charge, 0 for a membership only
+    pub delivery_amount: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionFunding {
+    /// Charged from a token account that approved the subscription PDA as delegate
+    Delegate,
+    /// Charged from lamports deposited in the subscription account
+    Prefunded,
+}
+
+/// Most beneficiaries a revenue split can have
+pub const MAX_BENEFICIARIES: usize = 8;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct RevenueShare {
+    pub beneficiary: [u8; 32],
+    pub bps: u16,
+}
+
+/// Beneficiaries of the treasury. Only the first `len` shares are used, the
+/// array keeps `Settings` at a fixed size.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct RevenueSplit {
+    pub len: u8,
+    pub shares: [RevenueShare; MAX_BENEFICIARIES],
+}
+
+impl RevenueSplit {
+    /// A split of `shares`, which must sum to `MAX_BPS`. No shares clears the split.
+    pub fn new(shares: &[RevenueShare]) -> Result<Self, PriceError> {
+        let total: u32 = shares.iter().map(|share| share.bps as u32).sum();
+        if shares.len() > MAX_BENEFICIARIES || (!shares.is_empty() && total != MAX_BPS as u32) {
+            return Err(PriceError::InvalidRevenueSplit);
+        }
+        let mut split = RevenueSplit {
+            len: shares.len() as u8,
+            ..RevenueSplit::default()
+        };
+        split.shares[..shares.len()].copy_from_slice(shares);
+        Ok(split)
+    }
+
+    pub fn active(&self) -> &[RevenueShare] {
+        &self.shares[..self.len as usize]
+    }
+
+    /// Each beneficiary's part of `lamports`, rounded down. The rounding dust
+    /// goes to the first beneficiary so the whole amount is paid out.
+    pub fn payouts(&self, lamports: u64) -> Vec<u64> {
+        let mut payouts: Vec<u64> = self
+            .active()
+            .iter()
+            .map(|share| (lamports as u128 * share.bps as u128 / MAX_BPS as u128) as u64)
+            .collect();
+        let paid: u64 = payouts.iter().sum();
+        if let Some(first) = payouts.first_mut() {
+            *first += lamports - paid;
+        }
+        payouts
+    }
+}
+
+/// What a Buy purchases.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BuySource {
+    /// The store token at the settings price
+    Store,
+    /// A catalog product by id
+    Product(u64),
+    /// A third-party listing, passed in the accounts
+    Listing,
+}
+
+/// One line of a `Checkout` cart.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CartItem {
+    pub product_id: u64,
+    pub quantity: u64,
+}
+
+/// Catalog item sold by Buy with a `product_id`, at its own price and out of a
+/// store token account of its own mint.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Product {
+    pub product_id: u64,
+    pub mint: [u8; 32],
+    pub price: u32,
+    pub stock: u64,
+    pub active: bool,
+}
+
+impl Product {
+    pub fn get_product_pubkey(product_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[PRODUCT_SEED.as_bytes(), &product_id.to_le_bytes()], &id())
+    }
+
+    pub fn is_pubkey_ok(product_id: u64, product_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_product_pubkey(product_id);
+        pubkey.to_bytes() == product_pubkey.to_bytes()
+    }
+
+    pub fn lamports_for(&self, amount: u64) -> Option<u64> {
+        amount.checked_mul(self.price as u64)
+    }
+}
+
+/// Tokens a third-party seller offers through Buy at its own price. They sit in
+/// an escrow token account owned by the listing PDA until bought or delisted.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub seller: [u8; 32],
+    pub listing_id: u64,
+    pub mint: [u8; 32],
+    /// Lamports per token, same unit as `Settings.updated_price`
+    pub price: u32,
+    /// Tokens left in the escrow
+    pub amount: u64,
+    pub escrow: [u8; 32],
+}
+
+impl Listing {
+    pub fn get_listing_pubkey(seller: &Pubkey, listing_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[LISTING_SEED.as_bytes(), &seller.to_bytes(), &listing_id.to_le_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(seller: &Pubkey, listing_id: u64, listing_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_listing_pubkey(seller, listing_id);
+        pubkey.to_bytes() == listing_pubkey.to_bytes()
+    }
+
+    pub fn lamports_for(&self, amount: u64) -> Option<u64> {
+        amount.checked_mul(self.price as u64)
+    }
+}
+
+/// A subscriber's recurring payment, charged by the permissionless
+/// `ChargeSubscription` crank once `next_charge` has passed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub subscriber: [u8; 32],
+    pub funding: SubscriptionFunding,
+    /// Token account charged for delegated subscriptions
+    pub payment_account: [u8; 32],
+    /// Token account receiving the delivered store tokens
+    pub deliver_to: [u8; 32],
+    pub next_charge: i64,
+    /// End of the period paid for so far
+    pub active_until: i64,
+}
+
+impl Subscription {
+    pub fn get_subscription_pubkey(subscriber: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[SUBSCRIPTION_SEED.as_bytes(), &subscriber.to_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(subscriber: &Pubkey, subscription_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_subscription_pubkey(subscriber);
+        pubkey.to_bytes() == subscription_pubkey.to_bytes()
+    }
+
+    pub fn is_active(&self, timestamp: i64) -> bool {
+        timestamp < self.active_until
+    }
+
+    /// Extends the subscription by one `period` after a charge at `timestamp`.
+    /// A lapsed subscription restarts from `timestamp`.
+    pub fn renew(&mut self, period: i64, timestamp: i64) -> Option<i64> {
+        self.active_until = self.active_until.max(timestamp).checked_add(period)?;
+        self.next_charge = self.active_until;
+        Some(self.active_until)
+    }
+}
+
+/// Longest memo an invoice can carry, in bytes
+pub const MAX_MEMO_LEN: usize = 64;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum InvoiceStatus {
+    Open,
+    Paid,
+    Expired,
+}
+
+/// A merchant's bill for `amount` of `mint`, or of lamports when `mint` is
+/// all zeros. The merchant's `invoice_id` and `memo` let a payment be matched
+/// to the order it settles.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Invoice {
+    pub merchant: [u8; 32],
+    pub invoice_id: u64,
+    pub amount: u64,
+    pub mint: [u8; 32],
+    pub memo: String,
+    /// Time the invoice can no longer be paid from, 0 for never
+    pub expiry: i64,
+    pub status: InvoiceStatus,
+    pub payer: [u8; 32],
+    pub paid_at: i64,
+}
+
+impl Invoice {
+    pub fn get_invoice_pubkey(merchant: &Pubkey, invoice_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[INVOICE_SEED.as_bytes(), &merchant.to_bytes(), &invoice_id.to_le_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(merchant: &Pubkey, invoice_id: u64, invoice_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_invoice_pubkey(merchant, invoice_id);
+        pubkey.to_bytes() == invoice_pubkey.to_bytes()
+    }
+
+    pub fn is_lamports(&self) -> bool {
+        self.mint == [0; 32]
+    }
+
+    /// Status at `timestamp`, counting an open invoice past its expiry as expired
+    pub fn status_at(&self, timestamp: i64) -> InvoiceStatus {
+        match self.status {
+            InvoiceStatus::Open if self.expiry != 0 && timestamp >= self.expiry => {
+                InvoiceStatus::Expired
+            }
+            status => status,
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EscrowStatus {
+    Funded,
+    Delivered,
+    Disputed,
+}
+
+/// Lamports a buyer paid for goods from `seller`, held by this PDA until the
+/// buyer releases them, the timeout after delivery passes, or the admin
+/// resolves a dispute. Closed once paid out.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Escrow {
+    pub buyer: [u8; 32],
+    pub seller: [u8; 32],
+    pub escrow_id: u64,
+    pub lamports: u64,
+    pub status: EscrowStatus,
+    /// Time anyone can release the escrow from, set when delivery is confirmed
+    pub release_after: i64,
+}
+
+impl Escrow {
+    pub fn get_escrow_pubkey(buyer: &Pubkey, escrow_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[ESCROW_SEED.as_bytes(), &buyer.to_bytes(), &escrow_id.to_le_bytes()],
+            &id(),
+        )
+    }
+
+    pub fn is_pubkey_ok(buyer: &Pubkey, escrow_id: u64, escrow_pubkey: &Pubkey) -> bool {
+        let (pubkey, _) = Self::get_escrow_pubkey(buyer, escrow_id);
+        pubkey.to_bytes() == escrow_pubkey.to_bytes()
+    }
+
+    /// The buyer can release an undisputed escrow at any time, anyone else only
+    /// once the timeout after delivery has passed.
+    pub fn can_release(&self, caller: &Pubkey, timestamp: i64) -> bool {
+        match self.status {
+            EscrowStatus::Funded => caller.to_bytes() == self.buyer,
+            EscrowStatus::Delivered => {
+                caller.to_bytes() == self.buyer || timestamp >= self.release_after
+            }
+            EscrowStatus::Disputed => false,
+        }
+    }
+
+    /// A dispute can be opened until the escrow becomes releasable by anyone,
+    /// so it cannot pre-empt a release the timeout already allows.
+    pub fn can_dispute(&self, timestamp: i64) -> bool {
+        match self.status {
+            EscrowStatus::Funded => true,
+            EscrowStatus::Delivered => timestamp < self.release_after,
+            EscrowStatus::Disputed => false,
+        }
+    }
+
+    /// Splits the escrowed lamports into the buyer's refund and the seller's share
+    pub fn split(&self, buyer_bps: u16) -> (u64, u64) {
+        let refund = (self.lamports as u128 * buyer_bps as u128 / MAX_BPS as u128) as u64;
+        (refund, self.lamports - refund)
+    }
+}
+
@@ -0,0 +1,71 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::instruction::Instruction;
+
+use crate::state::MAX_BPS;
+
+pub mod ed25519_program {
+    solana_program::declare_id!("Ed25519SigVerify111111111111111111111111111");
+}
+
+/// Signature offsets header size in Ed25519 program instruction data
+const SIGNATURE_OFFSETS_START: usize = 2;
+const SIGNATURE_OFFSETS_SIZE: usize = 14;
+/// Instruction index meaning "the Ed25519 instruction itself"
+const CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+/// Discount signed off-chain by the store admin. The borsh encoding of the
+/// voucher is the message the admin signs.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct Voucher {
+    pub buyer: [u8; 32],
+    pub discount_bps: u16,
+    /// Unix timestamp after which the voucher can't be redeemed
+    pub expiry: i64,
+    pub nonce: u64,
+}
+
+impl Voucher {
+    pub fn message(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.discount_bps <= MAX_BPS
+    }
+
+    pub fn discount(&self, lamports: u64) -> u64 {
+        (lamports as u128 * self.discount_bps as u128 / MAX_BPS as u128) as u64
+    }
+
+    /// Checks that `ix` is an Ed25519 program instruction verifying exactly one
+    /// signature of `signer` over this voucher, with all data inline.
+    pub fn is_signed_in(&self, ix: &Instruction, signer: &[u8; 32]) -> bool {
+        if ix.program_id != ed25519_program::id() {
+            return false;
+        }
+        let data = &ix.data;
+        if data.len() < SIGNATURE_OFFSETS_START + SIGNATURE_OFFSETS_SIZE || data[0] != 1 {
+            return false;
+        }
+        let read_u16 = |at: usize| {
+            let at = SIGNATURE_OFFSETS_START + at;
+            u16::from_le_bytes([data[at], data[at + 1]])
+        };
+        let signature_instruction_index = read_u16(2);
+        let public_key_offset = read_u16(4) as usize;
+        let public_key_instruction_index = read_u16(6);
+        let message_data_offset = read_u16(8) as usize;
+        let message_data_size = read_u16(10) as usize;
+        let message_instruction_index = read_u16(12);
+        if signature_instruction_index != CURRENT_INSTRUCTION
+            || public_key_instruction_index != CURRENT_INSTRUCTION
+            || message_instruction_index != CURRENT_INSTRUCTION
+        {
+            return false;
+        }
+
+        let public_key = data.get(public_key_offset..public_key_offset + 32);
+        let message = data.get(message_data_offset..message_data_offset + message_data_size);
+        public_key == Some(&signer[..]) && message == Some(&self.message()[..])
+    }
+}
@@ -1,8 +1,17 @@
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_store::{entrypoint::process_instruction, id, instruction::StoreInstruction };
+use solana_store::error::PriceError;
+use solana_store::events::{SettingsField, StoreEvent, EVENT_TAG};
+use solana_store::voucher::{ed25519_program, Voucher};
 use solana_store::{
-    state::{Price, Settings},
+    state::{
+        Accumulators, BuySource, CartItem, DutchAuction, Escrow, EscrowStatus, HistoryMode,
+        Inventory, Invoice, InvoiceStatus, LimitPeriod, Listing, Multisig, Order, Price, PriceEntry,
+        PriceHistory, Product, Quote, Receipt, Referral, RevenueShare, RevenueSplit, Settings,
+        SettingsChange, Side, Store, Subscription, SubscriptionFunding, SubscriptionTerms,
+        TradeLimits, Vesting, VestingSchedule, VoucherNonce, PRICE_HISTORY_LEN,
+    },
     PRICE_SEED,
 };
 use solana_program::pubkey::Pubkey;
@@ -11,6 +20,7 @@ use solana_sdk::signature::{Keypair, Signer};
 use solana_sdk::transaction::Transaction;
 use solana_program::instruction::{AccountMeta, Instruction};
 use solana_program::{
+    clock::Clock,
     hash::Hash,
     instruction::InstructionError,
     native_token::sol_to_lamports,
@@ -117,6 +127,134 @@ async fn mint_token(
     Ok(())
 }
 
+/// Creates a mint, a store token account holding `store_amount` and an empty
+/// token account of `user`. Returns the mint, store and user token accounts.
+async fn create_trade_accounts(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: &Hash,
+    user: &Pubkey,
+    store_amount: u64,
+) -> (Pubkey, Pubkey, Pubkey) {
+    let mint = Keypair::new();
+    let mint_authority = Keypair::new();
+    let store_token_account = Keypair::new();
+    let user_token_account = Keypair::new();
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+    let mint_rent = rent.minimum_balance(spl_token::state::Mint::LEN);
+
+    create_token_mint(
+        banks_client,
+        payer,
+        recent_blockhash,
+        mint_rent,
+        0,
+        &mint,
+        &mint_authority.pubkey(),
+    )
+        .await
+        .unwrap();
+    create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &store_token_account,
+        account_rent,
+        &mint.pubkey(),
+        &Store::get_store_pub(),
+    )
+        .await
+        .unwrap();
+    create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &user_token_account,
+        account_rent,
+        &mint.pubkey(),
+        user,
+    )
+        .await
+        .unwrap();
+    if store_amount > 0 {
+        mint_token(
+            banks_client,
+            payer,
+            recent_blockhash,
+            store_amount,
+            &mint.pubkey(),
+            &store_token_account.pubkey(),
+            &mint_authority,
+        )
+            .await
+            .unwrap();
+    }
+    (mint.pubkey(), store_token_account.pubkey(), user_token_account.pubkey())
+}
+
+/// Pins `mint` and `store_token` as the store mint and vault, `admin` paying.
+fn pin_store(admin: &Pubkey, mint: &Pubkey, store_token: &Pubkey) -> Instruction {
+    StoreInstruction::migrate_settings(admin, admin, mint, store_token)
+}
+
+/// Asserts the transaction fails with `error` raised by its instruction `index`.
+async fn assert_store_error(
+    banks_client: &mut BanksClient,
+    transaction: Transaction,
+    index: u8,
+    error: PriceError,
+) {
+    let err = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(index, InstructionError::Custom(error as u32)),
+    );
+}
+
+async fn token_amount(banks_client: &mut BanksClient, account: &Pubkey) -> u64 {
+    let account = banks_client.get_account(*account).await.unwrap().unwrap();
+    spl_token::state::Account::unpack(&account.data).unwrap().amount
+}
+
+async fn lamports(banks_client: &mut BanksClient, account: &Pubkey) -> u64 {
+    banks_client.get_account(*account).await.unwrap().map_or(0, |account| account.lamports)
+}
+
+/// Ed25519 program instruction verifying `signer`'s signature over the voucher,
+/// with the key, signature and message inline.
+fn ed25519_voucher_ix(signer: &Keypair, voucher: &Voucher) -> Instruction {
+    let message = voucher.message();
+    let signature = signer.sign_message(&message);
+    let (public_key_offset, signature_offset, message_offset) = (16u16, 48u16, 112u16);
+    let mut data = vec![1, 0];
+    for field in [
+        signature_offset,
+        u16::MAX,
+        public_key_offset,
+        u16::MAX,
+        message_offset,
+        message.len() as u16,
+        u16::MAX,
+    ]
+    .iter()
+    {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(&signer.pubkey().to_bytes());
+    data.extend_from_slice(signature.as_ref());
+    data.extend_from_slice(&message);
+    Instruction::new_with_bytes(ed25519_program::id(), &data, vec![])
+}
+
+fn system_account(lamports: u64) -> Account {
+    Account {
+        lamports,
+        owner: solana_program::system_program::id(),
+        ..Account::default()
+    }
+}
+
 
 #[tokio::test]
 async fn test_buy() {
@@ -130,12 +268,12 @@ async fn test_buy() {
     let decimals = 9;
     let user_account = Keypair::new();
     let pool_token_account = Keypair::new();
-    let pool_owner = Keypair::new();
+    let pool_owner = Store::get_store_pub();
     *&program.add_account(
         user_account.pubkey(),
         Account {
-            lamports:5000,
-            owner: id(),
+            lamports: sol_to_lamports(1.0),
+            owner: solana_program::system_program::id(),
             ..Account::default()
         },
     );
@@ -172,7 +310,7 @@ async fn test_buy() {
         &pool_token_account,
         account_rent,
         &token_mint_account.pubkey(),
-        &pool_owner.pubkey(),
+        &pool_owner,
     )
         .await
         .unwrap();
@@ -205,18 +343,2529 @@ async fn test_buy() {
     println!("custom token");
 
     let mut transaction = Transaction::new_with_payer(
-        &[StoreInstruction::buy(
-            &pool_owner.pubkey(),
-            &user_account.pubkey(),
-            &pool_token_account.pubkey(),
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(
+                &payer.pubkey(),
+                &token_mint_account.pubkey(),
+                &pool_token_account.pubkey(),
+            ),
+            StoreInstruction::buy(
+                &user_account.pubkey(),
+                &user_token_account.pubkey(),
+                &pool_token_account.pubkey(),
+                10,
+                None,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user_account], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let user_token = banks_client.get_account(user_token_account.pubkey()).await.unwrap().unwrap();
+    let user_token = spl_token::state::Account::unpack(&user_token.data).unwrap();
+    assert_eq!(user_token.amount, 10);
+    let store = banks_client.get_account(pool_owner).await.unwrap().unwrap();
+    assert_eq!(store.lamports, 10_000);
+}
+
+#[tokio::test]
+async fn test_sell_delegated() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+
+    let token_mint_account = Keypair::new();
+    let token_mint_authority = Keypair::new();
+    let decimals = 9;
+    let user_wallet = Keypair::new();
+    let user_token_account = Keypair::new();
+    let store_token_account = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(
+        store_pubkey,
+        Account {
+            lamports: sol_to_lamports(10.0),
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+    let mint_rent = rent.minimum_balance(spl_token::state::Mint::LEN);
+
+    create_token_mint(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        mint_rent,
+        decimals,
+        &token_mint_account,
+        &token_mint_authority.pubkey(),
+    )
+        .await
+        .unwrap();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &store_token_account,
+        account_rent,
+        &token_mint_account.pubkey(),
+        &store_pubkey,
+    )
+        .await
+        .unwrap();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &user_token_account,
+        account_rent,
+        &token_mint_account.pubkey(),
+        &user_wallet.pubkey(),
+    )
+        .await
+        .unwrap();
+    mint_token(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        1000,
+        &token_mint_account.pubkey(),
+        &user_token_account.pubkey(),
+        &token_mint_authority,
+    )
+        .await
+        .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1_000_000),
+            pin_store(
+                &payer.pubkey(),
+                &token_mint_account.pubkey(),
+                &store_token_account.pubkey(),
+            ),
+            spl_token::instruction::approve(
+                &spl_token::id(),
+                &user_token_account.pubkey(),
+                &store_pubkey,
+                &user_wallet.pubkey(),
+                &[],
+                600,
+            )
+                .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user_wallet], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Settled by the relayer alone, the user does not sign.
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell_delegated(
+            &user_wallet.pubkey(),
+            &user_token_account.pubkey(),
+            &store_token_account.pubkey(),
+            500,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let store_token = banks_client.get_account(store_token_account.pubkey()).await.unwrap().unwrap();
+    let store_token = spl_token::state::Account::unpack(&store_token.data).unwrap();
+    assert_eq!(store_token.amount, 500);
+    let user = banks_client.get_account(user_wallet.pubkey()).await.unwrap().unwrap();
+    assert_eq!(user.lamports, 500_000_000);
+
+    // Only 100 tokens of the allowance are left.
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell_delegated(
+            &user_wallet.pubkey(),
             &user_token_account.pubkey(),
-            &token_mint_account.pubkey(),
-            10
+            &store_token_account.pubkey(),
+            200,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
+#[tokio::test]
+async fn test_multisig_admin() {
+    let program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+
+    let signers = [Keypair::new(), Keypair::new(), Keypair::new()];
+    let signer_keys: Vec<Pubkey> = signers.iter().map(|signer| signer.pubkey()).collect();
+    let (multisig_pubkey, _) = Multisig::get_multisig_pubkey(&payer.pubkey());
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::create_multisig(&payer.pubkey(), 2, &signer_keys),
+            StoreInstruction::update_price(&payer.pubkey(), multisig_pubkey.to_bytes(), 10),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The former single-key admin has lost control.
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 11)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::update_price_multisig(
+            &multisig_pubkey,
+            &[&signer_keys[0]],
+            multisig_pubkey.to_bytes(),
+            12,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &signers[0]], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::update_price_multisig(
+            &multisig_pubkey,
+            &[&signer_keys[0], &signer_keys[2]],
+            multisig_pubkey.to_bytes(),
+            13,
         )],
         Some(&payer.pubkey()),
     );
-    transaction.sign(&[&payer, &pool_owner], recent_blockhash);
+    transaction.sign(&[&payer, &signers[0], &signers[2]], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let settings = banks_client.get_account(Settings::get_settings_pub()).await.unwrap().unwrap();
+    let settings = Settings::try_from_slice(&settings.data).unwrap();
+    assert_eq!(settings.updated_price, 13);
+}
+
+#[tokio::test]
+async fn test_roles() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(
+        store_pubkey,
+        Account {
+            lamports: sol_to_lamports(1.0),
+            owner: solana_program::system_program::id(),
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+
+    let price_manager = Keypair::new();
+    let treasurer = Keypair::new();
+    let pauser = Keypair::new();
+    let recipient = Pubkey::new_unique();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 10),
+            StoreInstruction::set_roles(
+                &payer.pubkey(),
+                &[],
+                &price_manager.pubkey(),
+                &treasurer.pubkey(),
+                &pauser.pubkey(),
+            ),
+            StoreInstruction::set_price(&price_manager.pubkey(), 20),
+            StoreInstruction::set_paused(&pauser.pubkey(), true),
+            StoreInstruction::withdraw(&treasurer.pubkey(), &[], &recipient, sol_to_lamports(0.5)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &price_manager, &pauser, &treasurer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let settings = banks_client.get_account(Settings::get_settings_pub()).await.unwrap().unwrap();
+    let settings = Settings::try_from_slice(&settings.data).unwrap();
+    assert_eq!(settings.updated_price, 20);
+    assert!(settings.paused);
+    let recipient_account = banks_client.get_account(recipient).await.unwrap().unwrap();
+    assert_eq!(recipient_account.lamports, sol_to_lamports(0.5));
+
+    // The pricing bot key cannot move funds.
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::withdraw(&price_manager.pubkey(), &[], &recipient, 1)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &price_manager], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+}
+
+#[tokio::test]
+async fn test_referral() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let referrer = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(referrer.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_referral_bps(&payer.pubkey(), 1000),
+            StoreInstruction::register_referrer(&referrer.pubkey()),
+            StoreInstruction::buy_with_referrer(
+                &user.pubkey(),
+                &user_token,
+                &store_token,
+                &referrer.pubkey(),
+                10,
+                None,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &referrer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // 10% of the 10_000 lamports went to the referrer, the rest to the store
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 10);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 9_000
+    );
+    let (referral_pubkey, _) = Referral::get_referral_pubkey(&referrer.pubkey());
+    let referral = banks_client.get_account(referral_pubkey).await.unwrap().unwrap();
+    let referral = Referral::try_from_slice(&referral.data).unwrap();
+    assert_eq!(referral.purchases, 1);
+    assert_eq!(referral.total_earned, 1_000);
+
+    let referrer_lamports = lamports(&mut banks_client, &referrer.pubkey()).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::claim_referral(&referrer.pubkey())],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &referrer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut banks_client, &referrer.pubkey()).await,
+        referrer_lamports + 1_000
+    );
+
+    // A buyer cannot refer themselves to get the share back
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::register_referrer(&user.pubkey()),
+            StoreInstruction::buy_with_referrer(
+                &user.pubkey(),
+                &user_token,
+                &store_token,
+                &user.pubkey(),
+                10,
+                None,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::SelfReferral).await;
+
+    // The referral follows the other trailing accounts, here the vesting account
+    let vesting = VestingSchedule {
+        start_ts: 0,
+        cliff_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::set_vesting(&payer.pubkey(), vesting),
+            StoreInstruction::open_vesting(&user.pubkey(), &mint),
+            StoreInstruction::with_referrer(
+                StoreInstruction::buy_vested(&user.pubkey(), &store_token, 10, None),
+                &referrer.pubkey(),
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(&user.pubkey());
+    assert_eq!(token_amount(&mut banks_client, &escrow_pubkey).await, 10);
+    let referral = banks_client.get_account(referral_pubkey).await.unwrap().unwrap();
+    let referral = Referral::try_from_slice(&referral.data).unwrap();
+    assert_eq!(referral.purchases, 2);
+    assert_eq!(referral.total_earned, 2_000);
+}
+
+#[tokio::test]
+async fn test_refund() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let referrer = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(referrer.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let user_lamports = lamports(&mut banks_client, &user.pubkey()).await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_referral_bps(&payer.pubkey(), 1000),
+            StoreInstruction::set_refund_window(&payer.pubkey(), 3600),
+            StoreInstruction::register_referrer(&referrer.pubkey()),
+            StoreInstruction::buy_with_referrer(
+                &user.pubkey(),
+                &user_token,
+                &store_token,
+                &referrer.pubkey(),
+                10,
+                Some(7),
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &referrer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 9_000
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::refund(&user.pubkey(), &user_token, &store_token, 7)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // The store pays back what it got, the referral share stays with the referrer
+    assert_eq!(lamports(&mut banks_client, &store_pubkey).await, sol_to_lamports(1.0));
+    assert_eq!(token_amount(&mut banks_client, &store_token).await, 100);
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 0);
+    assert_eq!(
+        lamports(&mut banks_client, &user.pubkey()).await,
+        user_lamports - 1_000
+    );
+    let (receipt_pubkey, _) = Receipt::get_receipt_pubkey(&user.pubkey(), 7);
+    assert_eq!(lamports(&mut banks_client, &receipt_pubkey).await, 0);
+
+    // Asking for a receipt once refunds are turned off
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::set_refund_window(&payer.pubkey(), 0),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 10, Some(8)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::RefundsDisabled).await;
+}
+
+#[tokio::test]
+async fn test_refund_product() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let (_, other_store_token, other_user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let (product_pubkey, _) = Product::get_product_pubkey(3);
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_refund_window(&payer.pubkey(), 3600),
+            StoreInstruction::add_product(&payer.pubkey(), &payer.pubkey(), 3, &mint, 700, 20),
+            StoreInstruction::buy_product(&user.pubkey(), &user_token, &store_token, 3, 5, Some(1)),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let (receipt_pubkey, _) = Receipt::get_receipt_pubkey(&user.pubkey(), 1);
+    let account = banks_client.get_account(receipt_pubkey).await.unwrap().unwrap();
+    let receipt = Receipt::try_from_slice(&account.data).unwrap();
+    assert_eq!(receipt.mint, mint.to_bytes());
+    assert_eq!(receipt.product_id, Some(3));
+    assert_eq!(receipt.lamports, 3_500);
+
+    // Other store-owned accounts, like product inventory, aren't sold at the store price
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy(&user.pubkey(), &other_user_token, &other_store_token, 10, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::WrongStoreVault).await;
+    assert_eq!(token_amount(&mut banks_client, &other_store_token).await, 100);
+
+    // Tokens of another mint the store also sells
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::refund_product(
+            &user.pubkey(),
+            &other_user_token,
+            &other_store_token,
+            1,
+            3,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    let err = banks_client.process_transaction(transaction).await.unwrap_err();
+    let token_mismatch = solana_store::error::TokenError::TokenMismatch as u32;
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(token_mismatch)),
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::refund_product(&user.pubkey(), &user_token, &store_token, 1, 3)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 0);
+    assert_eq!(token_amount(&mut banks_client, &store_token).await, 100);
+    assert_eq!(lamports(&mut banks_client, &store_pubkey).await, sol_to_lamports(1.0));
+    let account = banks_client.get_account(product_pubkey).await.unwrap().unwrap();
+    assert_eq!(Product::try_from_slice(&account.data).unwrap().stock, 20);
+}
+
+#[tokio::test]
+async fn test_checkout() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (first_mint, first_store_token, first_user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let (second_mint, second_store_token, second_user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let items = [
+        CartItem {
+            product_id: 1,
+            quantity: 2,
+        },
+        CartItem {
+            product_id: 2,
+            quantity: 3,
+        },
+    ];
+    let accounts = [
+        (first_store_token, first_user_token),
+        (second_store_token, second_user_token),
+    ];
+    let admin = payer.pubkey();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            StoreInstruction::add_product(&admin, &admin, 1, &first_mint, 100, 10),
+            StoreInstruction::add_product(&admin, &admin, 2, &second_mint, 200, 10),
+            StoreInstruction::checkout(&user.pubkey(), &items, &accounts),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &first_user_token).await, 2);
+    assert_eq!(token_amount(&mut banks_client, &second_user_token).await, 3);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 800
+    );
+
+    // The cart has no receipt to refund from, vesting escrow to deposit into,
+    // nor a wSOL account to pay from
+    let vesting = VestingSchedule {
+        start_ts: 0,
+        cliff_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let settings_changes = [
+        StoreInstruction::set_refund_window(&payer.pubkey(), 3600),
+        StoreInstruction::set_vesting(&payer.pubkey(), vesting),
+        StoreInstruction::set_quote(&payer.pubkey(), Quote::WrappedSol),
+    ];
+    for change in settings_changes {
+        let mut transaction = Transaction::new_with_payer(
+            &[change, StoreInstruction::checkout(&user.pubkey(), &items, &accounts)],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer, &user], recent_blockhash);
+        assert_store_error(&mut banks_client, transaction, 1, PriceError::CheckoutUnavailable)
+            .await;
+    }
+}
+
+#[tokio::test]
+async fn test_buy_listing_outside_store_rules() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let seller = Keypair::new();
+    let user = Keypair::new();
+    program.add_account(seller.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(Store::get_store_pub(), system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, seller_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &seller.pubkey(), 100)
+            .await;
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+    let (listing_pubkey, _) = Listing::get_listing_pubkey(&seller.pubkey(), 1);
+    let escrow = Keypair::new();
+    let user_token = Keypair::new();
+    for (account, owner) in [(&escrow, listing_pubkey), (&user_token, user.pubkey())] {
+        create_token_account(
+            &mut banks_client,
+            &payer,
+            &recent_blockhash,
+            account,
+            account_rent,
+            &mint,
+            &owner,
+        )
+            .await
+            .unwrap();
+    }
+    let listing = Listing {
+        seller: seller.pubkey().to_bytes(),
+        listing_id: 1,
+        mint: mint.to_bytes(),
+        price: 500,
+        amount: 20,
+        escrow: escrow.pubkey().to_bytes(),
+    };
+
+    // The seller bought their tokens from the store before vesting and limits were set
+    let limits = TradeLimits {
+        period: LimitPeriod::Epoch,
+        max_trade: 5,
+        max_tokens_sold: 0,
+        max_lamports_paid: 0,
+    };
+    let vesting = VestingSchedule {
+        start_ts: 0,
+        cliff_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy(&seller.pubkey(), &seller_token, &store_token, 20, None),
+            StoreInstruction::list_tokens(
+                &seller.pubkey(),
+                &seller_token,
+                &escrow.pubkey(),
+                1,
+                500,
+                20,
+            ),
+            StoreInstruction::set_limits(&payer.pubkey(), limits),
+            StoreInstruction::set_vesting(&payer.pubkey(), vesting),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &seller], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Delivered straight to the buyer, past the store's trade cap
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy_listing(
+            &user.pubkey(),
+            &user_token.pubkey(),
+            &listing,
+            10,
+            None,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token.pubkey()).await, 10);
+
+    // The store's own sales are still capped
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy_vested(&user.pubkey(), &store_token, 10, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::TradeTooLarge).await;
+}
+
+#[tokio::test]
+async fn test_circuit_breaker() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(Store::get_store_pub(), system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let limits = TradeLimits {
+        period: LimitPeriod::Day,
+        max_trade: 0,
+        max_tokens_sold: 30,
+        max_lamports_paid: 5_000,
+    };
+    let buy =
+        |amount| StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, amount, None);
+    let sell = |amount| StoreInstruction::sell(&user.pubkey(), &user_token, &store_token, amount);
+    let paused = |account: Account| Settings::try_from_slice(&account.data).unwrap().paused;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_limits(&payer.pubkey(), limits),
+            buy(20),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // 15 more would overshoot the 30 tokens the store may sell today
+    let mut transaction = Transaction::new_with_payer(&[buy(15)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::TradeLimitExceeded).await;
+
+    // The trade reaching the cap goes through and trips the breaker
+    let mut transaction = Transaction::new_with_payer(&[buy(10)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 30);
+    let settings = banks_client.get_account(Settings::get_settings_pub()).await.unwrap().unwrap();
+    assert!(paused(settings));
+    let mut transaction = Transaction::new_with_payer(&[sell(1)], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::StorePaused).await;
+
+    // Same for the 5_000 lamports the store may pay out
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::set_paused(&payer.pubkey(), false), sell(6)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::TradeLimitExceeded).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::set_paused(&payer.pubkey(), false), sell(5)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 25);
+    let settings = banks_client.get_account(Settings::get_settings_pub()).await.unwrap().unwrap();
+    assert!(paused(settings));
+}
+
+#[tokio::test]
+async fn test_claim_vested() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(Store::get_store_pub(), system_account(sol_to_lamports(1.0)));
+    let mut context = program.start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let (mint, store_token, user_token) = create_trade_accounts(
+        &mut context.banks_client,
+        &payer,
+        &context.last_blockhash,
+        &user.pubkey(),
+        100,
+    )
+        .await;
+
+    // Nothing before the cliff, then linear over 1000 seconds
+    let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    let vesting = VestingSchedule {
+        start_ts: clock.unix_timestamp,
+        cliff_ts: clock.unix_timestamp + 100,
+        end_ts: clock.unix_timestamp + 1000,
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_vesting(&payer.pubkey(), vesting),
+            StoreInstruction::open_vesting(&user.pubkey(), &mint),
+            StoreInstruction::buy_vested(&user.pubkey(), &store_token, 100, None),
+            StoreInstruction::claim(&user.pubkey(), &user_token),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let (escrow_pubkey, _) = Vesting::get_escrow_pubkey(&user.pubkey());
+    assert_eq!(token_amount(&mut context.banks_client, &escrow_pubkey).await, 100);
+    assert_eq!(token_amount(&mut context.banks_client, &user_token).await, 0);
+
+    for (elapsed, claimed) in [(500, 50), (1000, 100)] {
+        let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp = vesting.start_ts + elapsed;
+        context.set_sysvar(&clock);
+        let blockhash = context.get_new_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(
+            &[StoreInstruction::claim(&user.pubkey(), &user_token)],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer, &user], blockhash);
+        context.banks_client.process_transaction(transaction).await.unwrap();
+        assert_eq!(token_amount(&mut context.banks_client, &user_token).await, claimed);
+        assert_eq!(
+            token_amount(&mut context.banks_client, &escrow_pubkey).await,
+            100 - claimed
+        );
+    }
+    let (vesting_pubkey, _) = Vesting::get_vesting_pubkey(&user.pubkey());
+    let account = context.banks_client.get_account(vesting_pubkey).await.unwrap().unwrap();
+    let account = Vesting::try_from_slice(&account.data).unwrap();
+    assert_eq!((account.total, account.claimed), (100, 100));
+}
+
+#[tokio::test]
+async fn test_buy_with_voucher() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+
+    let voucher = |nonce, expiry| Voucher {
+        buyer: user.pubkey().to_bytes(),
+        discount_bps: 5000,
+        expiry,
+        nonce,
+    };
+    let buy = |amount, voucher: &Voucher| {
+        StoreInstruction::buy_with_voucher(
+            &user.pubkey(),
+            &user_token,
+            &store_token,
+            amount,
+            None,
+            voucher.clone(),
+        )
+    };
+
+    // Signed by the admin: half of the 10_000 lamports is waived. Lamports sent
+    // to the nonce account beforehand neither redeem it nor block redemption.
+    let valid = voucher(1, i64::MAX);
+    let (nonce_pubkey, _) = VoucherNonce::get_nonce_pubkey(&user.pubkey(), 1);
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            system_instruction::transfer(&payer.pubkey(), &nonce_pubkey, 1_000),
+            ed25519_voucher_ix(&payer, &valid),
+            buy(10, &valid),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 10);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 5_000
+    );
+
+    // The nonce was consumed by the first purchase
+    let mut transaction = Transaction::new_with_payer(
+        &[ed25519_voucher_ix(&payer, &valid), buy(5, &valid)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::VoucherRedeemed).await;
+    let nonce = banks_client.get_account(nonce_pubkey).await.unwrap().unwrap();
+    assert_eq!(nonce.owner, id());
+
+    // A valid signature by someone other than the admin
+    let forged = voucher(2, i64::MAX);
+    let mut transaction = Transaction::new_with_payer(
+        &[ed25519_voucher_ix(&user, &forged), buy(10, &forged)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::InvalidVoucher).await;
+
+    let expired = voucher(3, 1);
+    let mut transaction = Transaction::new_with_payer(
+        &[ed25519_voucher_ix(&payer, &expired), buy(10, &expired)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::VoucherExpired).await;
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 10);
+}
+
+#[tokio::test]
+async fn test_fill_sell_order_with_store() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+    let (order_pubkey, _) = Order::get_order_pubkey(&user.pubkey(), 1);
+    let escrow = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &escrow,
+        account_rent,
+        &mint,
+        &order_pubkey,
+    )
+        .await
+        .unwrap();
+    let foreign = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &foreign,
+        account_rent,
+        &mint,
+        &payer.pubkey(),
+    )
+        .await
+        .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 10, None),
+            StoreInstruction::place_order(
+                &user.pubkey(),
+                &user_token,
+                &store_token,
+                Some(&escrow.pubkey()),
+                1,
+                Side::Sell,
+                500,
+                10,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let order = Order {
+        owner: user.pubkey().to_bytes(),
+        order_id: 1,
+        side: Side::Sell,
+        price: 500,
+        amount: 10,
+        token_account: escrow.pubkey().to_bytes(),
+    };
+
+    // The store would pay for tokens delivered to someone else's account
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::fill_order_with_store(&order, &foreign.pubkey(), 10)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::WrongStoreVault).await;
+    assert_eq!(token_amount(&mut banks_client, &foreign.pubkey()).await, 0);
+
+    // The store doesn't buy back during a Dutch auction
+    let auction = DutchAuction {
+        start_price: 5000,
+        floor_price: 1000,
+        start_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::start_dutch_auction(&payer.pubkey(), auction),
+            StoreInstruction::fill_order_with_store(&order, &store_token, 10),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 1, PriceError::AuctionActive).await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::fill_order_with_store(&order, &store_token, 10)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &store_token).await, 100);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 10_000 - 5_000
+    );
+    let account = banks_client.get_account(Settings::get_settings_pub()).await.unwrap().unwrap();
+    let accumulators = Settings::try_from_slice(&account.data).unwrap().accumulators;
+    assert_eq!((accumulators.bought, accumulators.sold), (10, 10));
+    assert_eq!(accumulators.lamports, 15_000);
+    assert_eq!(accumulators.last_price, 500);
+}
+
+#[tokio::test]
+async fn test_fill_orders() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let seller = Keypair::new();
+    let buyer = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(seller.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(buyer.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, seller_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &seller.pubkey(), 100)
+            .await;
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+    let (sell_pubkey, _) = Order::get_order_pubkey(&seller.pubkey(), 1);
+    let escrow = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &escrow,
+        account_rent,
+        &mint,
+        &sell_pubkey,
+    )
+        .await
+        .unwrap();
+    let buyer_token = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &buyer_token,
+        account_rent,
+        &mint,
+        &buyer.pubkey(),
+    )
+        .await
+        .unwrap();
+
+    let order = |owner: &Keypair, order_id, side, price, amount, token_account: &Pubkey| Order {
+        owner: owner.pubkey().to_bytes(),
+        order_id,
+        side,
+        price,
+        amount,
+        token_account: token_account.to_bytes(),
+    };
+    let sell = order(&seller, 1, Side::Sell, 500, 20, &escrow.pubkey());
+    let low_buy = order(&buyer, 1, Side::Buy, 400, 10, &buyer_token.pubkey());
+    let high_buy = order(&buyer, 2, Side::Buy, 600, 10, &buyer_token.pubkey());
+    let place = |order: &Order, owner_token: &Pubkey, escrow: Option<&Pubkey>| {
+        StoreInstruction::place_order(
+            &Pubkey::new_from_array(order.owner),
+            owner_token,
+            &store_token,
+            escrow,
+            order.order_id,
+            order.side,
+            order.price,
+            order.amount,
+        )
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy(&seller.pubkey(), &seller_token, &store_token, 20, None),
+            place(&sell, &seller_token, Some(&escrow.pubkey())),
+            place(&low_buy, &buyer_token.pubkey(), None),
+            place(&high_buy, &buyer_token.pubkey(), None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &seller, &buyer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &escrow.pubkey()).await, 20);
+
+    // Bidding 400 doesn't reach the 500 asked
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::fill_orders(&sell, &low_buy, 10)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::OrderNotCrossing).await;
+
+    // 15 asked, the bid only takes 10, at the sell order's price. The filled bid
+    // closes and returns what it escrowed above that price.
+    let seller_lamports = lamports(&mut banks_client, &seller.pubkey()).await;
+    let buyer_lamports = lamports(&mut banks_client, &buyer.pubkey()).await;
+    let (high_buy_pubkey, _) = Order::get_order_pubkey(&buyer.pubkey(), 2);
+    let high_buy_lamports = lamports(&mut banks_client, &high_buy_pubkey).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::fill_orders(&sell, &high_buy, 15)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &buyer_token.pubkey()).await, 10);
+    assert_eq!(token_amount(&mut banks_client, &escrow.pubkey()).await, 10);
+    assert_eq!(lamports(&mut banks_client, &seller.pubkey()).await, seller_lamports + 5_000);
+    assert_eq!(
+        lamports(&mut banks_client, &buyer.pubkey()).await,
+        buyer_lamports + high_buy_lamports - 5_000
+    );
+    assert_eq!(lamports(&mut banks_client, &high_buy_pubkey).await, 0);
+    let rest = banks_client.get_account(sell_pubkey).await.unwrap().unwrap();
+    assert_eq!(Order::try_from_slice(&rest.data).unwrap().amount, 10);
+
+    // Cancelling returns the unfilled tokens and escrowed lamports
+    let (low_buy_pubkey, _) = Order::get_order_pubkey(&buyer.pubkey(), 1);
+    let buyer_lamports = lamports(&mut banks_client, &buyer.pubkey()).await;
+    let low_buy_lamports = lamports(&mut banks_client, &low_buy_pubkey).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::cancel_order(
+                &seller.pubkey(),
+                1,
+                &escrow.pubkey(),
+                Some(&seller_token),
+            ),
+            StoreInstruction::cancel_order(&buyer.pubkey(), 1, &buyer_token.pubkey(), None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &seller, &buyer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &seller_token).await, 10);
+    assert!(banks_client.get_account(escrow.pubkey()).await.unwrap().is_none());
+    assert_eq!(lamports(&mut banks_client, &sell_pubkey).await, 0);
+    assert!(low_buy_lamports >= 4_000);
+    assert_eq!(
+        lamports(&mut banks_client, &buyer.pubkey()).await,
+        buyer_lamports + low_buy_lamports
+    );
+    assert_eq!(lamports(&mut banks_client, &low_buy_pubkey).await, 0);
+}
+
+#[tokio::test]
+async fn test_sell_closed_during_auction() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+
+    let auction = DutchAuction {
+        start_price: 5000,
+        floor_price: 1000,
+        start_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 10, None),
+            StoreInstruction::start_dutch_auction(&payer.pubkey(), auction),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Selling back at the old fixed price would undercut the auction
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell(&user.pubkey(), &user_token, &store_token, 10)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::AuctionActive).await;
+}
+
+#[tokio::test]
+async fn test_sell_foreign_mint() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let rent = banks_client.get_rent().await.unwrap();
+
+    // Anyone can mint a junk token and open a store-owned account for it
+    let junk_mint = Keypair::new();
+    let junk_authority = Keypair::new();
+    let junk_store_token = Keypair::new();
+    let junk_user_token = Keypair::new();
+    create_token_mint(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        rent.minimum_balance(spl_token::state::Mint::LEN),
+        0,
+        &junk_mint,
+        &junk_authority.pubkey(),
+    )
+        .await
+        .unwrap();
+    let junk_accounts = [(&junk_store_token, store_pubkey), (&junk_user_token, user.pubkey())];
+    for (account, owner) in junk_accounts {
+        create_token_account(
+            &mut banks_client,
+            &payer,
+            &recent_blockhash,
+            account,
+            rent.minimum_balance(spl_token::state::Account::LEN),
+            &junk_mint.pubkey(),
+            &owner,
+        )
+            .await
+            .unwrap();
+    }
+    mint_token(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        100,
+        &junk_mint.pubkey(),
+        &junk_user_token.pubkey(),
+        &junk_authority,
+    )
+        .await
+        .unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 10, None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Selling junk tokens into the store's junk account would drain the treasury
+    let store_lamports = lamports(&mut banks_client, &store_pubkey).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell(
+            &user.pubkey(),
+            &junk_user_token.pubkey(),
+            &junk_store_token.pubkey(),
+            100,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::WrongStoreVault).await;
+    assert_eq!(lamports(&mut banks_client, &store_pubkey).await, store_lamports);
+    assert_eq!(token_amount(&mut banks_client, &junk_user_token.pubkey()).await, 100);
+
+    // Nor is the store mint paid for outside the pinned vault
+    let other_store_token = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &other_store_token,
+        rent.minimum_balance(spl_token::state::Account::LEN),
+        &mint,
+        &store_pubkey,
+    )
+        .await
+        .unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell(&user.pubkey(), &user_token, &other_store_token.pubkey(), 10)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::WrongStoreVault).await;
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 10);
+}
+
+#[tokio::test]
+async fn test_mint_on_demand() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let rent = banks_client.get_rent().await.unwrap();
+
+    // The store PDA is the mint authority and the mint takes the store token account slot
+    let mint = Keypair::new();
+    let user_token = Keypair::new();
+    create_token_mint(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        rent.minimum_balance(spl_token::state::Mint::LEN),
+        0,
+        &mint,
+        &store_pubkey,
+    )
+        .await
+        .unwrap();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &user_token,
+        rent.minimum_balance(spl_token::state::Account::LEN),
+        &mint.pubkey(),
+        &user.pubkey(),
+    )
+        .await
+        .unwrap();
+    let supply = |account: Account| spl_token::state::Mint::unpack(&account.data).unwrap().supply;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint.pubkey(), &mint.pubkey()),
+            StoreInstruction::set_inventory(&payer.pubkey(), Inventory::MintOnDemand),
+            StoreInstruction::buy(&user.pubkey(), &user_token.pubkey(), &mint.pubkey(), 10, None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token.pubkey()).await, 10);
+    let mint_account = banks_client.get_account(mint.pubkey()).await.unwrap().unwrap();
+    assert_eq!(supply(mint_account), 10);
+
+    // Selling burns the tokens instead of returning them to a vault
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell(&user.pubkey(), &user_token.pubkey(), &mint.pubkey(), 4)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token.pubkey()).await, 6);
+    let mint_account = banks_client.get_account(mint.pubkey()).await.unwrap().unwrap();
+    assert_eq!(supply(mint_account), 6);
+
+    // A mint other than the pinned one
+    let (_, _, other_token) = create_trade_accounts(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &user.pubkey(),
+        0,
+    )
+        .await;
+    let other_mint = banks_client.get_account(other_token).await.unwrap().unwrap();
+    let other_mint = spl_token::state::Account::unpack(&other_mint.data).unwrap().mint;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy(&user.pubkey(), &other_token, &other_mint, 10, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 0, PriceError::WrongStoreVault).await;
+}
+
+#[tokio::test]
+async fn test_wrapped_sol_quote() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+
+    // The user's wSOL account holds 50_000 lamports worth of wrapped SOL
+    let wsol = Keypair::new();
+    let rent = banks_client.get_rent().await.unwrap();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        &recent_blockhash,
+        &wsol,
+        rent.minimum_balance(spl_token::state::Account::LEN) + 50_000,
+        &spl_token::native_mint::id(),
+        &user.pubkey(),
+    )
+        .await
+        .unwrap();
+    let user_lamports = lamports(&mut banks_client, &user.pubkey()).await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_quote(&payer.pubkey(), Quote::WrappedSol),
+            StoreInstruction::buy_with_wsol(
+                &user.pubkey(),
+                &user_token,
+                &wsol.pubkey(),
+                &store_token,
+                10,
+                None,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 10);
+    assert_eq!(token_amount(&mut banks_client, &wsol.pubkey()).await, 40_000);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 10_000
+    );
+    // The temporary wSOL account is closed and its rent returned
+    let (temp_pubkey, _) = Store::get_wsol_pubkey(&user.pubkey());
+    assert_eq!(lamports(&mut banks_client, &temp_pubkey).await, 0);
+    assert_eq!(lamports(&mut banks_client, &user.pubkey()).await, user_lamports);
+
+    // Sell proceeds are synced into the wSOL balance
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::sell_with_wsol(
+            &user.pubkey(),
+            &user_token,
+            &wsol.pubkey(),
+            &store_token,
+            4,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 6);
+    assert_eq!(token_amount(&mut banks_client, &wsol.pubkey()).await, 44_000);
+    assert_eq!(lamports(&mut banks_client, &user.pubkey()).await, user_lamports);
+
+    // A plain Buy lacks the wSOL accounts the quote requires
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 1, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    let err = banks_client.process_transaction(transaction).await.unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys),
+    );
+
+    // Lamports sent to the temporary wSOL address don't block a purchase and
+    // end up with the user
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            system_instruction::transfer(&payer.pubkey(), &temp_pubkey, 1_000),
+            StoreInstruction::buy_with_wsol(
+                &user.pubkey(),
+                &user_token,
+                &wsol.pubkey(),
+                &store_token,
+                1,
+                None,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 7);
+    assert_eq!(token_amount(&mut banks_client, &wsol.pubkey()).await, 43_000);
+    assert_eq!(lamports(&mut banks_client, &temp_pubkey).await, 0);
+    assert_eq!(lamports(&mut banks_client, &user.pubkey()).await, user_lamports + 1_000);
+}
+
+#[tokio::test]
+async fn test_buy_to_associated() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(Store::get_store_pub(), system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, _) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let associated =
+        spl_associated_token_account::get_associated_token_address(&user.pubkey(), &mint);
+
+    // A first-time buyer has no token account yet
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::buy_to_associated(&user.pubkey(), &mint, &store_token, 10, None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &associated).await, 10);
+
+    // Creation is skipped once the account exists
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy_to_associated(&user.pubkey(), &mint, &store_token, 5, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &associated).await, 15);
+}
+
+#[tokio::test]
+async fn test_price_history_recorded() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(Store::get_store_pub(), system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let (history_pubkey, _) = PriceHistory::get_history_pubkey();
+    let with_history = |mut instruction: Instruction| {
+        instruction.accounts.push(AccountMeta::new(history_pubkey, false));
+        instruction
+    };
+    let change = SettingsChange {
+        admin: payer.pubkey().to_bytes(),
+        updated_price: 1500,
+        timelock_delay: 0,
+        ..SettingsChange::default()
+    };
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::create_price_history(&payer.pubkey()),
+            StoreInstruction::set_history_mode(&payer.pubkey(), HistoryMode::PriceChangesAndTrades),
+            StoreInstruction::set_price(&payer.pubkey(), 1200),
+            with_history(StoreInstruction::buy(
+                &user.pubkey(),
+                &user_token,
+                &store_token,
+                10,
+                None,
+            )),
+            with_history(StoreInstruction::sell(&user.pubkey(), &user_token, &store_token, 4)),
+            StoreInstruction::queue_settings_change(&payer.pubkey(), change),
+            StoreInstruction::execute_settings_change(None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let account = banks_client.get_account(history_pubkey).await.unwrap().unwrap();
+    let history = PriceHistory::try_from_slice(&account.data).unwrap();
+    let prices: Vec<u32> = history.chronological().iter().map(|entry| entry.price).collect();
+    assert_eq!(prices, vec![1200, 1200, 1200, 1500]);
+}
+
+#[tokio::test]
+async fn test_timelocked_settings_change() {
+    let program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let mut context = program.start_with_context().await;
+    let admin = context.payer.insecure_clone();
+    let (settings_pubkey, _) = Settings::get_settings_pubkey();
+
+    // Without a delay the first queued change can run at once and sets the delay
+    let change = SettingsChange {
+        admin: admin.pubkey().to_bytes(),
+        updated_price: 1000,
+        timelock_delay: 100,
+        ..SettingsChange::default()
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&admin.pubkey(), admin.pubkey().to_bytes(), 500),
+            StoreInstruction::add_product(
+                &admin.pubkey(),
+                &admin.pubkey(),
+                1,
+                &Pubkey::new_unique(),
+                700,
+                5,
+            ),
+            StoreInstruction::queue_settings_change(&admin.pubkey(), change),
+            StoreInstruction::execute_settings_change(None),
+        ],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let change = SettingsChange {
+        updated_price: 2000,
+        ..change
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::queue_settings_change(&admin.pubkey(), change),
+            StoreInstruction::execute_settings_change(None),
+        ],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    assert_store_error(
+        &mut context.banks_client,
+        transaction,
+        1,
+        PriceError::TimelockNotElapsed,
+    )
+    .await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::queue_settings_change(&admin.pubkey(), change)],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += 100;
+    context.set_sysvar(&clock);
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::execute_settings_change(None)],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let account = context.banks_client.get_account(settings_pubkey).await.unwrap().unwrap();
+    let settings = Settings::try_from_slice(&account.data).unwrap();
+    assert_eq!(settings.updated_price, 2000);
+    assert_eq!(settings.pending_eta, 0);
+
+    // Every instant way to change a price is closed while the delay is set
+    let auction = DutchAuction {
+        start_price: 5000,
+        floor_price: 1000,
+        start_ts: 0,
+        end_ts: i64::MAX,
+    };
+    let bypasses = [
+        StoreInstruction::update_price(&admin.pubkey(), admin.pubkey().to_bytes(), 1),
+        StoreInstruction::start_dutch_auction(&admin.pubkey(), auction),
+        StoreInstruction::update_product(&admin.pubkey(), 1, 1, 5, true),
+        // re-adding a removed product would set a new price
+        StoreInstruction::add_product(
+            &admin.pubkey(),
+            &admin.pubkey(),
+            2,
+            &Pubkey::new_unique(),
+            1,
+            5,
+        ),
+    ];
+    for instruction in bypasses {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&admin.pubkey()));
+        transaction.sign(&[&admin], context.last_blockhash);
+        assert_store_error(
+            &mut context.banks_client,
+            transaction,
+            0,
+            PriceError::TimelockActive,
+        )
+        .await;
+    }
+
+    // Stock and availability stay instant, the product price goes through the queue
+    let (product_pubkey, _) = Product::get_product_pubkey(1);
+    let change = SettingsChange {
+        product: product_pubkey.to_bytes(),
+        product_price: 900,
+        ..change
+    };
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_product(&admin.pubkey(), 1, 700, 3, false),
+            StoreInstruction::queue_settings_change(&admin.pubkey(), change),
+        ],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let account = context.banks_client.get_account(product_pubkey).await.unwrap().unwrap();
+    let product = Product::try_from_slice(&account.data).unwrap();
+    assert_eq!((product.price, product.stock, product.active), (700, 3, false));
+
+    clock.unix_timestamp += 100;
+    context.set_sysvar(&clock);
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::execute_settings_change(Some(1))],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let account = context.banks_client.get_account(product_pubkey).await.unwrap().unwrap();
+    let product = Product::try_from_slice(&account.data).unwrap();
+    assert_eq!((product.price, product.stock, product.active), (900, 3, false));
+    let account = context.banks_client.get_account(settings_pubkey).await.unwrap().unwrap();
+    let settings = Settings::try_from_slice(&account.data).unwrap();
+    assert_eq!(settings.updated_price, 2000);
+}
+
+#[test]
+fn test_multisig_rejects_duplicate_signers() {
+    let signer = Pubkey::new_unique().to_bytes();
+    let other = Pubkey::new_unique().to_bytes();
+    let multisig = Multisig {
+        threshold: 2,
+        signers: vec![signer, other],
+    };
+    assert!(multisig.is_valid());
+    // one key listed twice would meet a 2-of-2 threshold alone
+    let multisig = Multisig {
+        threshold: 2,
+        signers: vec![signer, signer],
+    };
+    assert!(!multisig.is_valid());
+}
+
+#[test]
+fn test_migrate_settings() {
+    let admin = Pubkey::new_unique().to_bytes();
+    let settings = Settings::new(admin, 7);
+    let data = settings.try_to_vec().unwrap();
+    assert_eq!(Settings::migrate(&data), Some(settings.clone()));
+    // the original layout held just the admin and the price
+    assert_eq!(Settings::migrate(&data[..36]), Some(settings));
+    assert_eq!(Settings::migrate(&data[..20]), None);
+}
+
+#[test]
+fn test_settings_updated_lists_changes() {
+    let old = Settings::new(Pubkey::new_unique().to_bytes(), 7);
+    let mut new = old.clone();
+    new.paused = true;
+    new.referral_bps = 250;
+    assert_eq!(
+        StoreEvent::settings_updated(&old, &new),
+        StoreEvent::SettingsUpdated {
+            changes: vec![SettingsField::Paused(true), SettingsField::ReferralBps(250)],
+        }
+    );
+}
+
+#[test]
+fn test_decode_events() {
+    let bought = StoreEvent::Bought {
+        user: Pubkey::new_unique().to_bytes(),
+        amount: 10,
+        lamports: 10_000,
+    };
+    let logs = vec![
+        format!("Program {} invoke [1]", id()),
+        format!(
+            "Program data: {} {}",
+            base64::encode(EVENT_TAG),
+            base64::encode(bought.try_to_vec().unwrap()),
+        ),
+        format!("Program data: {}", base64::encode(b"foreign")),
+        format!("Program {} success", id()),
+    ];
+    assert_eq!(StoreEvent::from_logs(&logs), vec![bought]);
+}
+
+#[test]
+fn test_dutch_auction_price() {
+    let auction = DutchAuction {
+        start_price: 1000,
+        floor_price: 400,
+        start_ts: 100,
+        end_ts: 400,
+    };
+    assert_eq!(auction.price_at(0), 1000);
+    assert_eq!(auction.price_at(100), 1000);
+    assert_eq!(auction.price_at(250), 700);
+    assert_eq!(auction.price_at(399), 402);
+    assert_eq!(auction.price_at(400), 400);
+    assert_eq!(auction.price_at(i64::MAX), 400);
+
+    let auction = DutchAuction {
+        start_price: u32::MAX,
+        floor_price: 0,
+        start_ts: i64::MIN,
+        end_ts: i64::MAX,
+    };
+    assert_eq!(auction.price_at(0), u32::MAX / 2 + 1);
+    assert_eq!(auction.price_at(i64::MAX - 1), 1);
+}
+
+#[test]
+fn test_vesting_schedule() {
+    let schedule = VestingSchedule {
+        start_ts: 1000,
+        cliff_ts: 1250,
+        end_ts: 2000,
+    };
+    assert!(schedule.is_valid());
+    assert_eq!(schedule.vested(1000, 1249), 0);
+    assert_eq!(schedule.vested(1000, 1250), 250);
+    assert_eq!(schedule.vested(1000, 1500), 500);
+    assert_eq!(schedule.vested(1000, 2000), 1000);
+    assert!(!VestingSchedule::default().is_enabled());
+}
+
+#[test]
+fn test_price_history_wraps() {
+    let entry = |price: u32| PriceEntry {
+        slot: price as u64,
+        timestamp: price as i64,
+        price,
+    };
+    let mut history = PriceHistory::default();
+    assert!(history.chronological().is_empty());
+    for price in 1..=3 {
+        history.push(entry(price));
+    }
+    assert_eq!(history.chronological(), vec![entry(1), entry(2), entry(3)]);
+
+    for price in 4..=PRICE_HISTORY_LEN as u32 + 2 {
+        history.push(entry(price));
+    }
+    let entries = history.chronological();
+    assert_eq!(entries.len(), PRICE_HISTORY_LEN);
+    assert_eq!(entries[0], entry(3));
+    assert_eq!(entries[PRICE_HISTORY_LEN - 1], entry(PRICE_HISTORY_LEN as u32 + 2));
+    assert_eq!(history.try_to_vec().unwrap().len(), PriceHistory::default().try_to_vec().unwrap().len());
+}
+
+#[test]
+fn test_accumulators_twap() {
+    let mut acc = Accumulators::default();
+    acc.record(Side::Buy, 100, 5, 500, 1000);
+    let start = acc.price_cumulative_at(1000);
+    acc.record(Side::Sell, 200, 2, 400, 1010);
+    acc.record(Side::Buy, 300, 1, 300, 1040);
+    let end = acc.price_cumulative_at(1050);
+
+    // 10s at 100, 30s at 200, 10s at 300
+    assert_eq!(end - start, 100 * 10 + 200 * 30 + 300 * 10);
+    assert_eq!(Accumulators::twap(start, 1000, end, 1050), Some(200));
+    assert_eq!(Accumulators::twap(start, 1000, end, 1000), None);
+    assert_eq!((acc.bought, acc.sold, acc.lamports), (6, 2, 1200));
+}
+
+#[test]
+fn test_trade_limits_window() {
+    let mut settings = Settings::new(Pubkey::new_unique().to_bytes(), 10);
+    settings.limits = TradeLimits {
+        max_trade: 50,
+        max_lamports_paid: 1000,
+        ..TradeLimits::default()
+    };
+    let day = 86_400;
+    assert!(settings.track_trade(51, 0, 510, 0, day).is_err());
+    assert!(!settings.track_trade(50, 0, 500, 0, day).unwrap());
+    // the cap can be reached but not overshot
+    assert!(matches!(
+        settings.clone().track_trade(50, 0, 510, 0, day + 10),
+        Err(PriceError::TradeLimitExceeded)
+    ));
+    assert!(settings.track_trade(50, 0, 500, 0, day + 10).unwrap());
+    // a new day starts a new window
+    assert!(!settings.track_trade(50, 0, 500, 0, 2 * day).unwrap());
+    assert_eq!(settings.window.lamports_paid, 500);
+}
+
+#[tokio::test]
+async fn test_delegated_subscription() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let (subscription_pubkey, _) = Subscription::get_subscription_pubkey(&user.pubkey());
+    let terms = SubscriptionTerms {
+        price: 10,
+        period: 3600,
+        payment_mint: mint.to_bytes(),
+        delivery_amount: 0,
+    };
+    let approve = |delegate: &Pubkey| {
+        spl_token::instruction::approve(
+            &spl_token::id(),
+            &user_token,
+            delegate,
+            &user.pubkey(),
+            &[],
+            10,
+        )
+        .unwrap()
+    };
+
+    // The store PDA as delegate is not enough
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_subscription_terms(&payer.pubkey(), terms),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 50, None),
+            StoreInstruction::subscribe(&user.pubkey(), &user_token, Some(&user_token), 0),
+            approve(&store_pubkey),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let account = banks_client.get_account(subscription_pubkey).await.unwrap().unwrap();
+    let subscription = Subscription::try_from_slice(&account.data).unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::charge_subscription(&subscription, Some(&store_token), None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let err = banks_client.process_transaction(transaction).await.unwrap_err();
+    let not_delegate = solana_store::error::TokenError::NotDelegate as u32;
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(not_delegate)),
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            approve(&subscription_pubkey),
+            StoreInstruction::charge_subscription(&subscription, Some(&store_token), None),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 40);
+    assert_eq!(token_amount(&mut banks_client, &store_token).await, 60);
+}
+
+#[tokio::test]
+async fn test_prefunded_subscription() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let mut context = program.start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let (mint, store_token, user_token) = create_trade_accounts(
+        &mut context.banks_client,
+        &payer,
+        &context.last_blockhash,
+        &user.pubkey(),
+        100,
+    )
+        .await;
+    let (subscription_pubkey, _) = Subscription::get_subscription_pubkey(&user.pubkey());
+    let terms = SubscriptionTerms {
+        price: 1000,
+        period: 3600,
+        payment_mint: [0; 32],
+        delivery_amount: 0,
+    };
+
+    // The deposit pays for two periods
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_subscription_terms(&payer.pubkey(), terms),
+            StoreInstruction::subscribe(&user.pubkey(), &user_token, None, 2500),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let account = context.banks_client.get_account(subscription_pubkey).await.unwrap().unwrap();
+    let subscription = Subscription::try_from_slice(&account.data).unwrap();
+    let deposit = account.lamports;
+    let charge = StoreInstruction::charge_subscription(&subscription, None, None);
+
+    let mut transaction = Transaction::new_with_payer(&[charge.clone()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut context.banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 1000
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &subscription_pubkey).await,
+        deposit - 1000
+    );
+    let account = context.banks_client.get_account(subscription_pubkey).await.unwrap().unwrap();
+    let charged = Subscription::try_from_slice(&account.data).unwrap();
+    assert_eq!(charged.next_charge, subscription.next_charge + 3600);
+    assert_eq!(charged.active_until, charged.next_charge);
+
+    // Nothing is due until the paid period ends
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[charge.clone()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], blockhash);
+    assert_store_error(&mut context.banks_client, transaction, 0, PriceError::ChargeNotDue)
+        .await;
+
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp = charged.next_charge;
+    context.set_sysvar(&clock);
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[charge.clone()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut context.banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 2000
+    );
+
+    // The rest of the deposit no longer covers a period
+    clock.unix_timestamp += 3600;
+    context.set_sysvar(&clock);
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[charge], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], blockhash);
+    let err = context.banks_client.process_transaction(transaction).await.unwrap_err();
+    let insufficient = solana_store::error::TokenError::InsufficientFunds as u32;
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(insufficient)),
+    );
+}
+
+#[test]
+fn test_subscription_renew() {
+    let mut subscription = Subscription {
+        subscriber: Pubkey::new_unique().to_bytes(),
+        funding: SubscriptionFunding::Prefunded,
+        payment_account: [0; 32],
+        deliver_to: Pubkey::new_unique().to_bytes(),
+        next_charge: 100,
+        active_until: 0,
+    };
+    assert!(!subscription.is_active(100));
+    assert_eq!(subscription.renew(30, 100), Some(130));
+    assert!(subscription.is_active(129));
+    // an early crank extends the paid period
+    assert_eq!(subscription.renew(30, 120), Some(160));
+    // a lapsed subscription restarts from the charge
+    assert_eq!(subscription.renew(30, 500), Some(530));
+    assert_eq!(subscription.next_charge, 530);
+}
+
+#[tokio::test]
+async fn test_pay_invoice() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let merchant = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(merchant.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let mut context = program.start_with_context().await;
+    let payer = context.payer.insecure_clone();
+    let (mint, store_token, user_token) = create_trade_accounts(
+        &mut context.banks_client,
+        &payer,
+        &context.last_blockhash,
+        &user.pubkey(),
+        100,
+    )
+        .await;
+    let rent = context.banks_client.get_rent().await.unwrap();
+    let merchant_token = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &payer,
+        &context.last_blockhash,
+        &merchant_token,
+        rent.minimum_balance(spl_token::state::Account::LEN),
+        &mint,
+        &merchant.pubkey(),
+    )
+        .await
+        .unwrap();
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    let load_invoice = |account: Account| Invoice::try_from_slice(&account.data).unwrap();
+    let (lamport_invoice, _) = Invoice::get_invoice_pubkey(&merchant.pubkey(), 1);
+    let (token_invoice, _) = Invoice::get_invoice_pubkey(&merchant.pubkey(), 2);
+    let (expiring_invoice, _) = Invoice::get_invoice_pubkey(&merchant.pubkey(), 3);
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            pin_store(&payer.pubkey(), &mint, &store_token),
+            StoreInstruction::set_commission_bps(&payer.pubkey(), 1_000),
+            StoreInstruction::buy(&user.pubkey(), &user_token, &store_token, 50, None),
+            StoreInstruction::create_invoice(&merchant.pubkey(), 1, 10_000, None, "".into(), 0),
+            StoreInstruction::create_invoice(
+                &merchant.pubkey(),
+                2,
+                20,
+                Some(&mint),
+                "order 2".into(),
+                0,
+            ),
+            StoreInstruction::create_invoice(
+                &merchant.pubkey(),
+                3,
+                10_000,
+                None,
+                "order 3".into(),
+                clock.unix_timestamp + 100,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user, &merchant], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let store_lamports = lamports(&mut context.banks_client, &store_pubkey).await;
+    let merchant_lamports = lamports(&mut context.banks_client, &merchant.pubkey()).await;
+    let account = context.banks_client.get_account(lamport_invoice).await.unwrap().unwrap();
+    let invoice = load_invoice(account);
+    let account = context.banks_client.get_account(token_invoice).await.unwrap().unwrap();
+    let invoice_in_tokens = load_invoice(account);
+
+    // The store keeps its commission of both payouts
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::pay_invoice(&user.pubkey(), &invoice),
+            StoreInstruction::pay_invoice_with_token(
+                &user.pubkey(),
+                &invoice_in_tokens,
+                &merchant_token.pubkey(),
+                &user_token,
+                &store_token,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut context.banks_client, &merchant.pubkey()).await,
+        merchant_lamports + 9_000
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &store_pubkey).await,
+        store_lamports + 1_000
+    );
+    assert_eq!(token_amount(&mut context.banks_client, &merchant_token.pubkey()).await, 18);
+    assert_eq!(token_amount(&mut context.banks_client, &store_token).await, 52);
+    assert_eq!(token_amount(&mut context.banks_client, &user_token).await, 30);
+    let account = context.banks_client.get_account(lamport_invoice).await.unwrap().unwrap();
+    let paid = load_invoice(account);
+    assert_eq!(paid.status, InvoiceStatus::Paid);
+    assert_eq!(paid.payer, user.pubkey().to_bytes());
+
+    // A paid invoice can't be paid twice, nor an expired one at all
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::pay_invoice(&user.pubkey(), &invoice)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], blockhash);
+    assert_store_error(&mut context.banks_client, transaction, 0, PriceError::InvoiceNotPayable)
+        .await;
+
+    let account = context.banks_client.get_account(expiring_invoice).await.unwrap().unwrap();
+    let expiring = load_invoice(account);
+    clock.unix_timestamp += 100;
+    context.set_sysvar(&clock);
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::pay_invoice(&user.pubkey(), &expiring)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], blockhash);
+    assert_store_error(&mut context.banks_client, transaction, 0, PriceError::InvoiceNotPayable)
+        .await;
+    assert_eq!(
+        lamports(&mut context.banks_client, &merchant.pubkey()).await,
+        merchant_lamports + 9_000
+    );
+}
+
+#[test]
+fn test_invoice_status() {
+    let mut invoice = Invoice {
+        merchant: Pubkey::new_unique().to_bytes(),
+        invoice_id: 7,
+        amount: 1000,
+        mint: [0; 32],
+        memo: "order 42".to_string(),
+        expiry: 100,
+        status: InvoiceStatus::Open,
+        payer: [0; 32],
+        paid_at: 0,
+    };
+    assert!(invoice.is_lamports());
+    assert_eq!(invoice.status_at(99), InvoiceStatus::Open);
+    assert_eq!(invoice.status_at(100), InvoiceStatus::Expired);
+    invoice.status = InvoiceStatus::Paid;
+    assert_eq!(invoice.status_at(100), InvoiceStatus::Paid);
+    invoice.status = InvoiceStatus::Open;
+    invoice.expiry = 0;
+    assert_eq!(invoice.status_at(i64::MAX), InvoiceStatus::Open);
+}
+
+#[tokio::test]
+async fn test_escrow() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let buyer = Keypair::new();
+    let seller = Keypair::new();
+    let stranger = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    for pubkey in [buyer.pubkey(), seller.pubkey(), stranger.pubkey(), store_pubkey] {
+        program.add_account(pubkey, system_account(sol_to_lamports(1.0)));
+    }
+    let mut context = program.start_with_context().await;
+    let admin = context.payer.insecure_clone();
+    let open = |escrow_id| {
+        StoreInstruction::open_escrow(&buyer.pubkey(), &seller.pubkey(), escrow_id, 10_000)
+    };
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&admin.pubkey(), admin.pubkey().to_bytes(), 1000),
+            StoreInstruction::set_escrow_timeout(&admin.pubkey(), 100),
+            StoreInstruction::set_commission_bps(&admin.pubkey(), 1_000),
+            open(1),
+            open(2),
+            open(3),
+        ],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin, &buyer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let mut escrows = vec![];
+    for escrow_id in 1..=3 {
+        let (escrow_pubkey, _) = Escrow::get_escrow_pubkey(&buyer.pubkey(), escrow_id);
+        let account = context.banks_client.get_account(escrow_pubkey).await.unwrap().unwrap();
+        escrows.push((escrow_pubkey, Escrow::try_from_slice(&account.data).unwrap()));
+    }
+    let mut escrows = escrows.into_iter();
+    let (released_pubkey, released) = escrows.next().unwrap();
+    let (disputed_pubkey, disputed) = escrows.next().unwrap();
+    let (late_pubkey, late) = escrows.next().unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::confirm_delivery(&released),
+            StoreInstruction::confirm_delivery(&disputed),
+            StoreInstruction::confirm_delivery(&late),
+            StoreInstruction::open_dispute(&buyer.pubkey(), &disputed),
+        ],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin, &seller, &buyer], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let account = context.banks_client.get_account(late_pubkey).await.unwrap().unwrap();
+    let release_after = Escrow::try_from_slice(&account.data).unwrap().release_after;
+
+    // Only the buyer can release before the timeout, and nobody a disputed escrow
+    let rejected = [
+        (
+            StoreInstruction::release_escrow(&stranger.pubkey(), &released),
+            &stranger,
+            PriceError::EscrowLocked,
+        ),
+        (
+            StoreInstruction::release_escrow(&buyer.pubkey(), &disputed),
+            &buyer,
+            PriceError::EscrowLocked,
+        ),
+        (
+            StoreInstruction::resolve_dispute(&stranger.pubkey(), &disputed, 5_000),
+            &stranger,
+            PriceError::AdminRequired,
+        ),
+    ];
+    for (instruction, signer, error) in rejected {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&admin.pubkey()));
+        transaction.sign(&[&admin, signer], context.last_blockhash);
+        assert_store_error(&mut context.banks_client, transaction, 0, error).await;
+    }
+
+    // Half back to the buyer, the store keeps its commission of the other half
+    let escrow_lamports = lamports(&mut context.banks_client, &disputed_pubkey).await;
+    let buyer_lamports = lamports(&mut context.banks_client, &buyer.pubkey()).await;
+    let seller_lamports = lamports(&mut context.banks_client, &seller.pubkey()).await;
+    let store_lamports = lamports(&mut context.banks_client, &store_pubkey).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::resolve_dispute(&admin.pubkey(), &disputed, 5_000)],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut context.banks_client, &buyer.pubkey()).await,
+        buyer_lamports + escrow_lamports - 5_000
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &seller.pubkey()).await,
+        seller_lamports + 4_500
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &store_pubkey).await,
+        store_lamports + 500
+    );
+    assert_eq!(lamports(&mut context.banks_client, &disputed_pubkey).await, 0);
+
+    // After the timeout anyone can release, and it's too late to dispute
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp = release_after;
+    context.set_sysvar(&clock);
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::open_dispute(&seller.pubkey(), &late)],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin, &seller], blockhash);
+    assert_store_error(
+        &mut context.banks_client,
+        transaction,
+        0,
+        PriceError::DisputeWindowClosed,
+    )
+    .await;
+
+    let escrow_lamports = lamports(&mut context.banks_client, &released_pubkey).await;
+    let buyer_lamports = lamports(&mut context.banks_client, &buyer.pubkey()).await;
+    let seller_lamports = lamports(&mut context.banks_client, &seller.pubkey()).await;
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::release_escrow(&stranger.pubkey(), &released)],
+        Some(&admin.pubkey()),
+    );
+    transaction.sign(&[&admin, &stranger], blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(
+        lamports(&mut context.banks_client, &seller.pubkey()).await,
+        seller_lamports + 9_000
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &buyer.pubkey()).await,
+        buyer_lamports + escrow_lamports - 10_000
+    );
+    assert_eq!(
+        lamports(&mut context.banks_client, &store_pubkey).await,
+        store_lamports + 1_500
+    );
+    assert_eq!(lamports(&mut context.banks_client, &released_pubkey).await, 0);
+}
+
+#[test]
+fn test_escrow_release_and_split() {
+    let buyer = Pubkey::new_unique();
+    let stranger = Pubkey::new_unique();
+    let mut escrow = Escrow {
+        buyer: buyer.to_bytes(),
+        seller: Pubkey::new_unique().to_bytes(),
+        escrow_id: 1,
+        lamports: 1001,
+        status: EscrowStatus::Funded,
+        release_after: 0,
+    };
+    assert!(escrow.can_release(&buyer, 0));
+    assert!(!escrow.can_release(&stranger, i64::MAX));
+    assert!(escrow.can_dispute(i64::MAX));
+    escrow.status = EscrowStatus::Delivered;
+    escrow.release_after = 100;
+    assert!(!escrow.can_release(&stranger, 99));
+    assert!(escrow.can_release(&stranger, 100));
+    // the seller can't block a release the timeout already allows
+    assert!(escrow.can_dispute(99));
+    assert!(!escrow.can_dispute(100));
+    escrow.status = EscrowStatus::Disputed;
+    assert!(!escrow.can_release(&buyer, 100));
+    assert!(!escrow.can_dispute(0));
+    assert_eq!(escrow.split(5_000), (500, 501));
+    assert_eq!(escrow.split(10_000), (1001, 0));
+}
+
+#[test]
+fn test_revenue_split_payouts() {
+    let share = |bps| RevenueShare {
+        beneficiary: Pubkey::new_unique().to_bytes(),
+        bps,
+    };
+    assert!(RevenueSplit::new(&[share(5_000), share(4_999)]).is_err());
+    assert!(RevenueSplit::new(&[share(1_250); 9]).is_err());
+    assert_eq!(RevenueSplit::new(&[]).unwrap().payouts(100), Vec::<u64>::new());
+
+    let split = RevenueSplit::new(&[share(3_333), share(3_333), share(3_334)]).unwrap();
+    assert_eq!(split.active().len(), 3);
+    // the rounding dust goes to the first beneficiary
+    assert_eq!(split.payouts(100), vec![34, 33, 33]);
+    assert_eq!(split.payouts(10_000), vec![3_333, 3_333, 3_334]);
+    assert_eq!(split.payouts(0), vec![0, 0, 0]);
+}
+
+#[tokio::test]
+async fn test_buy_partial() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let user = Keypair::new();
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(user.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let (mint, store_token, user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 5)
+            .await;
+    let (product_mint, product_store_token, product_user_token) =
+        create_trade_accounts(&mut banks_client, &payer, &recent_blockhash, &user.pubkey(), 100)
+            .await;
+    let mut buy_product = StoreInstruction::buy_product(
+        &user.pubkey(),
+        &product_user_token,
+        &product_store_token,
+        1,
+        10,
+        None,
+    );
+    buy_product.data = StoreInstruction::Buy {
+        amount: 10,
+        receipt_id: None,
+        source: BuySource::Product(1),
+        allow_partial: true,
+        create_associated: false,
+    }
+    .try_to_vec()
+    .unwrap();
+
+    // The vault holds 5 and the product has 3 in stock, out of 10 asked each time
+    let admin = payer.pubkey();
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&admin, admin.to_bytes(), 1000),
+            pin_store(&admin, &mint, &store_token),
+            StoreInstruction::add_product(&admin, &admin, 1, &product_mint, 700, 3),
+            StoreInstruction::buy_partial(&user.pubkey(), &user_token, &store_token, 10, None),
+            buy_product,
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(token_amount(&mut banks_client, &user_token).await, 5);
+    assert_eq!(token_amount(&mut banks_client, &product_user_token).await, 3);
+    assert_eq!(
+        lamports(&mut banks_client, &store_pubkey).await,
+        sol_to_lamports(1.0) + 5 * 1000 + 3 * 700
+    );
+    let (product_pubkey, _) = Product::get_product_pubkey(1);
+    let account = banks_client.get_account(product_pubkey).await.unwrap().unwrap();
+    assert_eq!(Product::try_from_slice(&account.data).unwrap().stock, 0);
+
+    // Nothing left to fill
+    let mut transaction = Transaction::new_with_payer(
+        &[StoreInstruction::buy_partial(&user.pubkey(), &user_token, &store_token, 10, None)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user], recent_blockhash);
+    let err = banks_client.process_transaction(transaction).await.unwrap_err();
+    let insufficient_funds = solana_store::error::TokenError::InsufficientFunds as u32;
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(insufficient_funds)),
+    );
+}
+
+#[tokio::test]
+async fn test_distribute() {
+    let mut program = ProgramTest::new("solana_store", id(), processor!(process_instruction));
+    let stranger = Keypair::new();
+    let beneficiaries = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let store_pubkey = Store::get_store_pub();
+    program.add_account(stranger.pubkey(), system_account(sol_to_lamports(1.0)));
+    program.add_account(store_pubkey, system_account(sol_to_lamports(1.0)));
+    for beneficiary in beneficiaries {
+        program.add_account(beneficiary, system_account(sol_to_lamports(1.0)));
+    }
+    let (mut banks_client, payer, recent_blockhash) = program.start().await;
+    let shares = || {
+        beneficiaries
+            .iter()
+            .map(|beneficiary| RevenueShare {
+                beneficiary: beneficiary.to_bytes(),
+                bps: 5_000,
+            })
+            .collect()
+    };
+
+    // Only the treasurer decides when the treasury is paid out
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            StoreInstruction::set_revenue_split(&payer.pubkey(), shares()),
+            StoreInstruction::distribute(&stranger.pubkey(), &[], &beneficiaries),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &stranger], recent_blockhash);
+    assert_store_error(&mut banks_client, transaction, 2, PriceError::AdminRequired).await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            StoreInstruction::update_price(&payer.pubkey(), payer.pubkey().to_bytes(), 1000),
+            StoreInstruction::set_revenue_split(&payer.pubkey(), shares()),
+            StoreInstruction::distribute(&payer.pubkey(), &[], &beneficiaries),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
+    let reserve = banks_client.get_rent().await.unwrap().minimum_balance(0);
+    let payout = (sol_to_lamports(1.0) - reserve) / 2;
+    assert_eq!(lamports(&mut banks_client, &store_pubkey).await, reserve);
+    for beneficiary in &beneficiaries {
+        assert_eq!(
+            lamports(&mut banks_client, beneficiary).await,
+            sol_to_lamports(1.0) + payout
+        );
+    }
 }
 
 // #[tokio::test]
@@ -1,3 +1,4 @@
+use borsh::BorshDeserialize;
 use solana_client::{
     client_error::{ClientError, ClientErrorKind},
     rpc_client::RpcClient,
@@ -14,6 +15,7 @@ use solana_sdk::{
 use spl_token::state::{Account, Mint};
 use std::convert::TryInto;
 use solana_sdk::commitment_config::CommitmentConfig;
+use solana_store::state::Settings;
 
 pub struct Client {
     program_id: Pubkey,
@@ -71,5 +73,19 @@ impl Client {
         self.create_account::<Account>(payer, &spl_token::ID)
     }
 
+    pub fn get_settings(&self) -> Result<Settings, ClientError> {
+        let data = self.rpc_client.get_account_data(&Settings::get_settings_pub())?;
+        Settings::try_from_slice(&data)
+            .map_err(|err| ClientErrorKind::Custom(err.to_string()).into())
+    }
+
+    /// Price per token Buy would charge at the latest block time.
+    pub fn quote_price(&self) -> Result<u32, ClientError> {
+        let settings = self.get_settings()?;
+        let slot = self.rpc_client.get_slot()?;
+        let timestamp = self.rpc_client.get_block_time(slot)?;
+        Ok(settings.buy_price_at(timestamp))
+    }
+
 }
 